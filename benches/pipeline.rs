@@ -0,0 +1,85 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+//! Pipelined echo benchmark.
+//!
+//! Clients write a large batch of small requests without waiting for
+//! responses, while the server reads them back out of the receive buffer
+//! one request at a time and echoes each. With many requests buffered at
+//! once, the cost of consuming from the front of a buffer dominates.
+//!
+//! Run with `cargo bench`.
+//!
+//! The Vec buffer the ring buffer replaced is no longer in the tree. To
+//! compare against it, run this file on the revision before the ring buffer
+//! with the `buffer_shrink` call and `Shrink` removed, best with fewer
+//! `REQUESTS`: at the sizes below it takes well over ten minutes.
+
+
+extern crate alnio;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alnio::{Builder, Callbacks, Connection, Shrink};
+
+
+const REQUEST_LEN: usize = 64;
+const REQUESTS: usize = 64 * 1024;
+const CLIENTS: usize = 4;
+const ROUNDS: usize = 5;
+
+
+fn main() {
+    for &(name, shrink) in &[("when-empty", Shrink::default()),
+                             ("fit", Shrink::Fit),
+                             ("never", Shrink::Never)] {
+        let elapsed = run(shrink);
+        let bytes = (REQUEST_LEN * REQUESTS * CLIENTS * ROUNDS) as f64;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        println!("pipeline/{:<12} {:>8.1} ms {:>8.1} MiB/s",
+                 name,
+                 secs * 1000.0,
+                 bytes / secs / (1024.0 * 1024.0));
+    }
+}
+
+fn run(shrink: Shrink) -> Duration {
+    let h = Callbacks::new().on_recv(|conn: &Connection| {
+        let mut req = [0u8; REQUEST_LEN];
+        while conn.bytes_avail().unwrap() >= REQUEST_LEN {
+            conn.recv(&mut req).unwrap();
+            conn.send(&req).unwrap();
+        }
+    });
+
+    let server = Builder::new()
+        .handler(h)
+        .buffer_shrink(shrink)
+        .start("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr();
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS).map(|_| thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let batch = vec![7u8; REQUEST_LEN * REQUESTS];
+        let mut echoed = vec![0u8; batch.len()];
+        for _ in 0..ROUNDS {
+            let mut writer = stream.try_clone().unwrap();
+            let batch = batch.clone();
+            let w = thread::spawn(move || writer.write_all(&batch).unwrap());
+            stream.read_exact(&mut echoed).unwrap();
+            w.join().unwrap();
+        }
+    })).collect();
+
+    for c in clients { c.join().unwrap(); }
+    start.elapsed()
+}
@@ -5,65 +5,587 @@
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
 
 
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::IoSlice;
 use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
 
 use parking_lot::Mutex;
 
 
+/// Smallest amount of storage a Buffer allocates.
+const MIN_CAPACITY: usize = 4096;
+
+
+/// A growable ring buffer, chained with shared segments.
+///
+/// Bytes are appended at the tail and consumed from the head without
+/// moving anything else in the buffer, storage is only ever copied when
+/// it grows or shrinks. Shared segments are queued by reference in between
+/// the copied bytes, so the same payload can be sent to any number of
+/// connections without being copied into each of their buffers. File
+/// regions are queued the same way and never read into userspace at all.
 pub struct Buffer {
-    mutex: Mutex<Vec<u8>>
+    mutex: Mutex<Chain>,
+    shrink: Shrink
+}
+
+/// When a Buffer gives back storage it has grown to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shrink {
+    /// Storage is kept for the lifetime of the buffer.
+    Never,
+    /// Whenever the buffer becomes empty, storage larger than the passed
+    /// number of bytes is released down to it.
+    WhenEmpty(usize),
+    /// Storage is halved whenever less than a quarter of it is in use.
+    Fit
+}
+
+struct Chain {
+    ring: Ring,
+    /// Order the ring's bytes and shared segments were appended in.
+    segments: VecDeque<Segment>,
+    len: usize,
+    /// Files whose region has been consumed in full, oldest first.
+    sent_files: Vec<File>
+}
+
+enum Segment {
+    /// The next bytes in the ring.
+    Copied(usize),
+    /// A shared payload, and how much of it has been consumed.
+    Shared(Arc<[u8]>, usize),
+    /// A region of a file, written with sendfile.
+    File(FileRegion)
+}
+
+struct FileRegion {
+    file: File,
+    offset: u64,
+    len: usize,
+    /// How much of the region has been consumed.
+    sent: usize
+}
+
+struct Ring {
+    storage: Vec<u8>,
+    head: usize,
+    len: usize
 }
 
 impl Buffer {
-    /// Creates a new Buffer.
-    pub fn new() -> Buffer { Buffer { mutex: Mutex::new(Vec::new()) } }
+    /// Creates a new Buffer that gives back storage according to `shrink`.
+    pub fn with_shrink(shrink: Shrink) -> Buffer {
+        Buffer {
+            mutex: Mutex::new(Chain {
+                ring: Ring { storage: Vec::new(), head: 0, len: 0 },
+                segments: VecDeque::new(),
+                len: 0,
+                sent_files: Vec::new()
+            }),
+            shrink
+        }
+    }
 
     /// Inserts `buf` at the end of this Buffer, allocating if needed.
     pub fn append(&self, buf: &[u8]) {
-        let mut v = self.mutex.lock();
-        v.extend_from_slice(buf);
+        self.mutex.lock().append(buf);
+    }
+
+    /// Inserts each of `bufs` at the end of this Buffer in order, nothing
+    /// appended concurrently ends up in between them.
+    pub fn append_vectored(&self, bufs: &[IoSlice]) {
+        let mut chain = self.mutex.lock();
+        for buf in bufs { chain.append(buf); }
+    }
+
+    /// Queues `buf` at the end of this Buffer without copying it.
+    pub fn append_shared(&self, buf: Arc<[u8]>) {
+        if buf.is_empty() { return; }
+
+        let mut chain = self.mutex.lock();
+        chain.len += buf.len();
+        chain.segments.push_back(Segment::Shared(buf, 0));
+    }
+
+    /// Queues `len` bytes of `file`, starting at `offset`, at the end of
+    /// this Buffer without reading them.
+    pub fn append_file(&self, file: File, offset: u64, len: usize) {
+        let mut chain = self.mutex.lock();
+        chain.len += len;
+        chain.segments.push_back(Segment::File(FileRegion {
+            file,
+            offset,
+            len,
+            sent: 0
+        }));
+    }
+
+    /// Returns the files whose regions have been consumed since the last
+    /// call, in the order they were appended.
+    pub fn take_sent_files(&self) -> Vec<File> {
+        let mut chain = self.mutex.lock();
+        mem::take(&mut chain.sent_files)
     }
 
     /// Returns the number of elements in this buffer.
     pub fn len(&self) -> usize {
-        let v = self.mutex.lock();
-        v.len()
+        let chain = self.mutex.lock();
+        chain.len
+    }
+
+    /// Removes up to `len` elements from the front of this Buffer.
+    pub fn consume(&self, len: usize) {
+        let mut chain = self.mutex.lock();
+        chain.consume(len, |_| { });
+        chain.ring.shrink(self.shrink);
+    }
+
+    /// Removes up to `buf.len()` elements from the front of this Buffer
+    /// and copies them into `buf` returning the total amount copied.
+    pub fn take(&self, buf: &mut [u8]) -> usize {
+        let mut chain = self.mutex.lock();
+        let taken = chain.take(buf);
+        chain.ring.shrink(self.shrink);
+        taken
+    }
+
+    /// Removes exactly `buf.len()` elements from the front of this Buffer
+    /// and copies them into `buf`, or leaves the Buffer untouched and
+    /// returns false if it holds fewer than that.
+    pub fn take_exact(&self, buf: &mut [u8]) -> bool {
+        let mut chain = self.mutex.lock();
+        if chain.len < buf.len() { return false; }
+
+        chain.take(buf);
+        chain.ring.shrink(self.shrink);
+        true
+    }
+
+    /// Copies up to `buf.len()` elements from the front of this Buffer into
+    /// `buf` without removing them, returning the total amount copied.
+    ///
+    /// Only meant for buffers holding nothing but copied bytes, such as
+    /// receive buffers.
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let chain = self.mutex.lock();
+        let (a, b) = chain.ring.slices();
+        let a_len = cmp::min(buf.len(), a.len());
+        let b_len = cmp::min(buf.len() - a_len, b.len());
+        buf[..a_len].copy_from_slice(&a[..a_len]);
+        buf[a_len..a_len + b_len].copy_from_slice(&b[..b_len]);
+        a_len + b_len
+    }
+
+    /// Calls `f` with the contents of this Buffer as a single slice, then
+    /// removes however many elements `f` returns from the front, returning
+    /// the total amount removed.
+    ///
+    /// Only meant for buffers holding nothing but copied bytes, such as
+    /// receive buffers.
+    pub fn consume_with<F>(&self, f: F) -> usize where F: FnOnce(&[u8]) -> usize {
+        let mut chain = self.mutex.lock();
+        chain.ring.make_contiguous();
+
+        let consumed = cmp::min(f(chain.ring.slices().0), chain.len);
+        chain.consume(consumed, |_| { });
+        chain.ring.shrink(self.shrink);
+        consumed
+    }
+
+    /// Returns the shared segment at the front of this Buffer, and how much
+    /// of it has been consumed, if it has at least `min_len` bytes left.
+    pub fn front_shared(&self, min_len: usize) -> Option<(Arc<[u8]>, usize)> {
+        let chain = self.mutex.lock();
+        match chain.segments.front() {
+            Some(&Segment::Shared(ref buf, pos)) if buf.len() - pos >= min_len => {
+                Some((buf.clone(), pos))
+            }
+            _ => None
+        }
+    }
+
+    /// Calls `f` with the fd, current offset and remaining length of the
+    /// file region at the front of this Buffer, if there is one.
+    pub fn with_front_file<F, R>(&self, f: F) -> Option<R>
+        where F: FnOnce(RawFd, u64, usize) -> R
+    {
+        let chain = self.mutex.lock();
+        match chain.segments.front() {
+            Some(Segment::File(region)) => {
+                let offset = region.offset + region.sent as u64;
+                Some(f(region.file.as_raw_fd(), offset, region.len - region.sent))
+            }
+            _ => None
+        }
+    }
+
+    /// Calls `f` with up to `max` slices that make up the front of this
+    /// Buffer, in order, stopping short of the first file region.
+    pub fn with_io_slices<F, R>(&self, max: usize, f: F) -> R
+        where F: FnOnce(&[IoSlice]) -> R
+    {
+        let chain = self.mutex.lock();
+        let (a, b) = chain.ring.slices();
+        let mut ring_pos = 0;
+
+        let mut slices = Vec::with_capacity(cmp::min(max, chain.segments.len() + 1));
+        for segment in chain.segments.iter() {
+            if slices.len() >= max { break; }
+            match *segment {
+                Segment::Copied(len) => {
+                    // A run of copied bytes is split in two when it wraps
+                    // around the end of the ring's storage.
+                    let end = ring_pos + len;
+                    if ring_pos < a.len() {
+                        slices.push(IoSlice::new(&a[ring_pos..cmp::min(end, a.len())]));
+                    }
+                    if end > a.len() && slices.len() < max {
+                        let start = ring_pos.saturating_sub(a.len());
+                        slices.push(IoSlice::new(&b[start..end - a.len()]));
+                    }
+                    ring_pos = end;
+                }
+                Segment::Shared(ref buf, pos) => slices.push(IoSlice::new(&buf[pos..])),
+                Segment::File(_) => break
+            }
+        }
+
+        f(&slices)
+    }
+}
+
+impl Chain {
+    /// Removes up to `buf.len()` elements from the front and copies them
+    /// into `buf`.
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let mut taken = 0;
+        self.consume(buf.len(), |b| {
+            buf[taken..taken + b.len()].copy_from_slice(b);
+            taken += b.len();
+        });
+        taken
+    }
+
+    fn append(&mut self, buf: &[u8]) {
+        if buf.is_empty() { return; }
+
+        self.ring.append(buf);
+        self.len += buf.len();
+
+        if let Some(&mut Segment::Copied(ref mut len)) = self.segments.back_mut() {
+            *len += buf.len();
+            return;
+        }
+        self.segments.push_back(Segment::Copied(buf.len()));
+    }
+
+    /// Removes up to `len` elements from the front, calling `f` with each
+    /// run of them in order. File regions are skipped over without reading
+    /// them.
+    fn consume<F>(&mut self, len: usize, mut f: F) where F: FnMut(&[u8]) {
+        let mut left = cmp::min(len, self.len);
+        self.len -= left;
+
+        while left > 0 {
+            let done = match self.segments.front_mut() {
+                Some(&mut Segment::Copied(ref mut seg_len)) => {
+                    let n = cmp::min(left, *seg_len);
+                    {
+                        let (a, b) = self.ring.slices();
+                        let a_len = cmp::min(n, a.len());
+                        f(&a[..a_len]);
+                        if n > a_len { f(&b[..n - a_len]); }
+                    }
+                    self.ring.consume(n);
+                    *seg_len -= n;
+                    left -= n;
+                    *seg_len == 0
+                }
+                Some(&mut Segment::Shared(ref buf, ref mut pos)) => {
+                    let n = cmp::min(left, buf.len() - *pos);
+                    f(&buf[*pos..*pos + n]);
+                    *pos += n;
+                    left -= n;
+                    *pos == buf.len()
+                }
+                Some(&mut Segment::File(ref mut region)) => {
+                    let n = cmp::min(left, region.len - region.sent);
+                    region.sent += n;
+                    left -= n;
+                    region.sent == region.len
+                }
+                None => break
+            };
+
+            if done {
+                if let Some(Segment::File(region)) = self.segments.pop_front() {
+                    self.sent_files.push(region.file);
+                }
+            }
+        }
+    }
+}
+
+impl Ring {
+    fn capacity(&self) -> usize { self.storage.len() }
+
+    fn wrap(&self, pos: usize) -> usize {
+        if pos >= self.capacity() { pos - self.capacity() } else { pos }
+    }
+
+    fn append(&mut self, buf: &[u8]) {
+        self.reserve(buf.len());
+
+        let tail = self.wrap(self.head + self.len);
+        self.write_at(tail, buf);
+        self.len += buf.len();
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.head = self.wrap(self.head + len);
+        self.len -= len;
+    }
+
+    fn slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+        if end <= self.capacity() {
+            (&self.storage[self.head..end], &[])
+        } else {
+            let b_len = end - self.capacity();
+            (&self.storage[self.head..], &self.storage[..b_len])
+        }
     }
 
-    /// Inserts `buf` at the beginning of this Buffer, allocating if needed
-    /// and shifting all elements to the right.
-    pub fn prepend(&self, buf: &[u8]) {
-        let mut v = self.mutex.lock();
+    /// Moves the contents so they no longer wrap around the end of storage.
+    fn make_contiguous(&mut self) {
+        if self.head + self.len > self.capacity() {
+            let capacity = self.capacity();
+            self.resize(capacity);
+        }
+    }
 
-        let new_len = buf.len() + (*v).len();
-        let mut nv = Vec::<u8>::with_capacity(new_len);
-        nv.extend_from_slice(buf);
-        nv.extend_from_slice(&(*v)[..]);
-        mem::swap(&mut *v, &mut nv);
+    /// Copies `buf` into storage starting at `pos`, wrapping around the end.
+    fn write_at(&mut self, pos: usize, buf: &[u8]) {
+        let a_len = cmp::min(buf.len(), self.capacity() - pos);
+        self.storage[pos..pos + a_len].copy_from_slice(&buf[..a_len]);
+        self.storage[..buf.len() - a_len].copy_from_slice(&buf[a_len..]);
     }
 
-    /// Extracts up to elements `[0..len]` from this Buffer.
-    pub fn extract(&self, len: usize) -> Vec<u8> {
-        let mut v = self.mutex.lock();
+    /// Makes sure there is room for `additional` more elements.
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.len + additional;
+        if needed <= self.capacity() { return; }
 
-        let len = if v.len() < len { v.len() } else { len };
-        let mut ret_buf = Vec::<u8>::with_capacity(len);
-        ret_buf.extend_from_slice(&v[0..len]);
+        let capacity = cmp::max(needed.next_power_of_two(), MIN_CAPACITY);
+        self.resize(capacity);
+    }
 
-        unsafe {
-            let remaining_len = v.len() - len;
-            for x in 0..remaining_len {
-                let new = *v.get_unchecked(x + len);
-                let old = v.get_unchecked_mut(x);
-                *old = new;
+    fn shrink(&mut self, shrink: Shrink) {
+        match shrink {
+            Shrink::Never => { }
+            Shrink::WhenEmpty(keep) => {
+                if self.len == 0 && self.capacity() > keep {
+                    self.resize(keep);
+                }
+            }
+            Shrink::Fit => {
+                let capacity = self.capacity();
+                if capacity > MIN_CAPACITY && self.len < capacity / 4 {
+                    self.resize(capacity / 2);
+                }
             }
+        }
+
+        if self.len == 0 { self.head = 0; }
+    }
 
-            v.set_len(remaining_len);
+    /// Moves the contents into new storage of `capacity` elements.
+    fn resize(&mut self, capacity: usize) {
+        let mut storage = vec![0u8; capacity];
+        {
+            let (a, b) = self.slices();
+            storage[..a.len()].copy_from_slice(a);
+            storage[a.len()..a.len() + b.len()].copy_from_slice(b);
         }
 
-        ret_buf
+        self.storage = storage;
+        self.head = 0;
     }
 }
 
-unsafe impl Send for Buffer { }
-unsafe impl Sync for Buffer { }
+impl Default for Shrink {
+    fn default() -> Shrink { Shrink::WhenEmpty(64 * 1024) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::IoSlice;
+    use std::sync::Arc;
+
+    use super::{Buffer, Ring, Shrink, MIN_CAPACITY};
+
+
+    fn contents(ring: &Ring) -> Vec<u8> {
+        let (a, b) = ring.slices();
+        let mut v = a.to_vec();
+        v.extend_from_slice(b);
+        v
+    }
+
+    fn pattern(start: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| start.wrapping_add(i as u8)).collect()
+    }
+
+    /// Leaves `ring` holding `len` bytes that wrap around the end of its
+    /// storage, returning them.
+    fn wrapped(ring: &mut Ring, len: usize) -> Vec<u8> {
+        let front = MIN_CAPACITY - 10;
+        ring.append(&vec![0u8; front]);
+        ring.consume(front);
+        let bytes = pattern(1, len);
+        ring.append(&bytes);
+        bytes
+    }
+
+    fn new_ring() -> Ring { Ring { storage: Vec::new(), head: 0, len: 0 } }
+
+    #[test]
+    fn ring_wraps_around_end_of_storage() {
+        let mut ring = new_ring();
+        let bytes = wrapped(&mut ring, 100);
+
+        assert_eq!(ring.capacity(), MIN_CAPACITY);
+        let (a, b) = ring.slices();
+        assert_eq!((a.len(), b.len()), (10, 90));
+        assert_eq!(contents(&ring), bytes);
+    }
+
+    #[test]
+    fn ring_reserve_unwraps_into_larger_storage() {
+        let mut ring = new_ring();
+        let mut bytes = wrapped(&mut ring, 100);
+
+        let more = pattern(7, MIN_CAPACITY);
+        ring.append(&more);
+        bytes.extend_from_slice(&more);
+
+        assert_eq!(ring.capacity(), 2 * MIN_CAPACITY);
+        assert_eq!(ring.head, 0);
+        assert_eq!(ring.slices().1.len(), 0);
+        assert_eq!(contents(&ring), bytes);
+    }
+
+    #[test]
+    fn ring_make_contiguous() {
+        let mut ring = new_ring();
+        let bytes = wrapped(&mut ring, 100);
+
+        ring.make_contiguous();
+        assert_eq!(ring.capacity(), MIN_CAPACITY);
+        assert_eq!(ring.slices(), (&bytes[..], &[][..]));
+
+        // Already contiguous, nothing moves.
+        ring.consume(50);
+        ring.make_contiguous();
+        assert_eq!(ring.head, 50);
+        assert_eq!(contents(&ring), &bytes[50..]);
+    }
+
+    #[test]
+    fn ring_resize_smaller_keeps_wrapped_contents() {
+        let mut ring = new_ring();
+        ring.append(&vec![0u8; 4 * MIN_CAPACITY]);
+        ring.consume(4 * MIN_CAPACITY - 10);
+        let bytes = pattern(3, 100);
+        ring.append(&bytes[..]);
+        ring.consume(10 - 3);
+        assert!(!ring.slices().1.is_empty());
+
+        ring.shrink(Shrink::Fit);
+        assert_eq!(ring.capacity(), 2 * MIN_CAPACITY);
+        assert_eq!(ring.head, 0);
+        assert_eq!(contents(&ring), [&[0u8; 3][..], &bytes[..]].concat());
+    }
+
+    /// Leaves `buf` holding a single run of copied bytes that wraps around
+    /// the end of the ring's storage, returning them. Emptying the buffer
+    /// would move the ring's head back to the start, so 4 bytes are kept.
+    fn wrapped_buffer(buf: &Buffer, len: usize) -> Vec<u8> {
+        let front = MIN_CAPACITY - 10;
+        buf.append(&vec![0u8; front]);
+        buf.consume(front - 4);
+        let bytes = pattern(1, len);
+        buf.append(&bytes);
+        [&[0u8; 4][..], &bytes[..]].concat()
+    }
+
+    fn io_slices(buf: &Buffer, max: usize) -> Vec<Vec<u8>> {
+        buf.with_io_slices(max, |slices: &[IoSlice]| {
+            slices.iter().map(|s| s.to_vec()).collect()
+        })
+    }
+
+    #[test]
+    fn io_slices_split_wrapped_run() {
+        let buf = Buffer::with_shrink(Shrink::Never);
+        let bytes = wrapped_buffer(&buf, 100);
+
+        let slices = io_slices(&buf, 8);
+        assert_eq!(slices, vec![bytes[..14].to_vec(), bytes[14..].to_vec()]);
+
+        // The second half of the run does not fit.
+        assert_eq!(io_slices(&buf, 1), vec![bytes[..14].to_vec()]);
+    }
+
+    #[test]
+    fn io_slices_split_wrapped_run_after_shared() {
+        let buf = Buffer::with_shrink(Shrink::Never);
+        let front = MIN_CAPACITY - 10;
+        buf.append(&vec![0u8; front]);
+        buf.consume(front - 4);
+
+        let shared: Arc<[u8]> = Arc::from(&b"shared"[..]);
+        buf.append_shared(shared);
+        let bytes = pattern(1, 20);
+        buf.append(&bytes);
+
+        assert_eq!(io_slices(&buf, 8), vec![vec![0u8; 4],
+                                             b"shared".to_vec(),
+                                             bytes[..10].to_vec(),
+                                             bytes[10..].to_vec()]);
+    }
+
+    #[test]
+    fn consume_across_mixed_segments() {
+        let buf = Buffer::with_shrink(Shrink::Never);
+        buf.append(b"abc");
+        buf.append_shared(Arc::from(&b"defg"[..]));
+        buf.append_file(File::open("/dev/null").unwrap(), 0, 5);
+        buf.append(b"hij");
+        assert_eq!(buf.len(), 15);
+
+        let mut out = [0u8; 5];
+        assert_eq!(buf.take(&mut out), 5);
+        assert_eq!(&out, b"abcde");
+        assert_eq!(buf.len(), 10);
+
+        // The rest of the shared segment, the file region skipped over
+        // without being read, and part of the copied bytes after it.
+        buf.consume(9);
+        assert_eq!(buf.len(), 1);
+        assert_eq!(buf.take_sent_files().len(), 1);
+
+        let mut out = [0u8; 4];
+        assert_eq!(buf.take(&mut out), 1);
+        assert_eq!(out[0], b'j');
+        assert_eq!(buf.len(), 0);
+        assert_eq!(io_slices(&buf, 8), Vec::<Vec<u8>>::new());
+    }
+}
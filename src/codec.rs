@@ -0,0 +1,402 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::cmp;
+use std::io::{self, Error, ErrorKind};
+
+
+/// Largest frame the built-in codecs accept unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+
+/// Splits a stream of bytes into frames.
+pub trait Decoder: Send + Sync {
+    /// Decodes the first frame in `buf`, returning it along with the number
+    /// of bytes it took up, or `None` if `buf` does not hold a complete
+    /// frame yet.
+    ///
+    /// Errors are reported to `Handler::on_error`, after which the
+    /// connection is shutdown: the bytes that failed to decode are still at
+    /// the front of its receive buffer, and the frames after them can no
+    /// longer be told apart.
+    fn decode(&self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>>;
+}
+
+/// Turns frames into a stream of bytes its `Decoder` counterpart can split.
+pub trait Encoder: Send + Sync {
+    /// Appends `frame`, encoded, to `dst`.
+    fn encode(&self, frame: &[u8], dst: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// A `Decoder` and `Encoder` pair, implemented for every type that is both.
+///
+/// Once a connection has a codec, complete frames are delivered to
+/// `Handler::on_message` instead of raw bytes to `Handler::on_recv`, and
+/// `Connection::send_frame` encodes frames with it. A codec's largest frame
+/// must fit within the receive buffer's high watermark, or it can never be
+/// received in full.
+pub trait Codec: Decoder + Encoder { }
+
+impl<T: Decoder + Encoder> Codec for T { }
+
+
+/// Frames terminated by `\n`, with an optional `\r` before it. Decoded
+/// frames exclude the terminator, encoding appends `\n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lines {
+    max_len: usize
+}
+
+/// Byte order of a `LengthPrefixed` frame's length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little
+}
+
+/// Width of a `LengthPrefixed` frame's length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefixWidth {
+    U16,
+    U32
+}
+
+/// Frames preceded by their length as a fixed width integer, not counting
+/// the length itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthPrefixed {
+    width: PrefixWidth,
+    endian: Endian,
+    max_len: usize
+}
+
+/// Frames preceded by their length as an unsigned LEB128 varint, as used
+/// to delimit protocol buffers messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Varint {
+    max_len: usize
+}
+
+
+impl Lines {
+    /// Creates a new Lines codec accepting lines of up to
+    /// `DEFAULT_MAX_FRAME_LEN` bytes.
+    pub fn new() -> Lines { Lines { max_len: DEFAULT_MAX_FRAME_LEN } }
+
+    /// Sets the longest line accepted, not counting its terminator.
+    pub fn max_len(mut self, max_len: usize) -> Lines {
+        self.max_len = max_len;
+        self
+    }
+}
+
+impl Default for Lines {
+    fn default() -> Lines { Lines::new() }
+}
+
+impl Decoder for Lines {
+    fn decode(&self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        // Allows for the terminator and the carriage return before it.
+        let limit = self.max_len.saturating_add(2);
+        let pos = match buf[..cmp::min(buf.len(), limit)].iter().position(|&b| b == b'\n') {
+            Some(pos) => pos,
+            None if buf.len() >= limit => return Err(too_long()),
+            None => return Ok(None)
+        };
+
+        let end = if pos > 0 && buf[pos - 1] == b'\r' { pos - 1 } else { pos };
+        if end > self.max_len { return Err(too_long()); }
+        Ok(Some((buf[..end].to_vec(), pos + 1)))
+    }
+}
+
+impl Encoder for Lines {
+    fn encode(&self, frame: &[u8], dst: &mut Vec<u8>) -> io::Result<()> {
+        if frame.len() > self.max_len { return Err(too_long()); }
+        if frame.contains(&b'\n') {
+            return Err(Error::new(ErrorKind::InvalidInput, "Line contains a newline"));
+        }
+
+        dst.extend_from_slice(frame);
+        dst.push(b'\n');
+        Ok(())
+    }
+}
+
+impl LengthPrefixed {
+    /// Creates a new LengthPrefixed codec accepting frames of up to
+    /// `DEFAULT_MAX_FRAME_LEN` bytes, or as many as `width` can express.
+    pub fn new(width: PrefixWidth, endian: Endian) -> LengthPrefixed {
+        LengthPrefixed {
+            width,
+            endian,
+            max_len: DEFAULT_MAX_FRAME_LEN
+        }
+    }
+
+    /// Sets the largest frame accepted, not counting its length.
+    pub fn max_len(mut self, max_len: usize) -> LengthPrefixed {
+        self.max_len = max_len;
+        self
+    }
+
+    fn prefix_len(&self) -> usize {
+        match self.width {
+            PrefixWidth::U16 => 2,
+            PrefixWidth::U32 => 4
+        }
+    }
+
+    fn max_frame_len(&self) -> usize {
+        let width_max = match self.width {
+            PrefixWidth::U16 => u16::MAX as u64,
+            PrefixWidth::U32 => u32::MAX as u64
+        };
+        if (self.max_len as u64) < width_max { self.max_len } else { width_max as usize }
+    }
+}
+
+impl Decoder for LengthPrefixed {
+    fn decode(&self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        let prefix_len = self.prefix_len();
+        if buf.len() < prefix_len { return Ok(None); }
+
+        let mut len: u64 = 0;
+        for i in 0..prefix_len {
+            let b = match self.endian {
+                Endian::Big => buf[i],
+                Endian::Little => buf[prefix_len - 1 - i]
+            };
+            len = (len << 8) | b as u64;
+        }
+
+        if len > self.max_frame_len() as u64 { return Err(too_long()); }
+        let end = prefix_len + len as usize;
+        if buf.len() < end { return Ok(None); }
+
+        Ok(Some((buf[prefix_len..end].to_vec(), end)))
+    }
+}
+
+impl Encoder for LengthPrefixed {
+    fn encode(&self, frame: &[u8], dst: &mut Vec<u8>) -> io::Result<()> {
+        if frame.len() > self.max_frame_len() { return Err(too_long()); }
+
+        let prefix_len = self.prefix_len();
+        let len = frame.len() as u64;
+        for i in 0..prefix_len {
+            let shift = match self.endian {
+                Endian::Big => 8 * (prefix_len - 1 - i),
+                Endian::Little => 8 * i
+            };
+            dst.push((len >> shift) as u8);
+        }
+
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+impl Varint {
+    /// Creates a new Varint codec accepting frames of up to
+    /// `DEFAULT_MAX_FRAME_LEN` bytes.
+    pub fn new() -> Varint { Varint { max_len: DEFAULT_MAX_FRAME_LEN } }
+
+    /// Sets the largest frame accepted, not counting its length.
+    pub fn max_len(mut self, max_len: usize) -> Varint {
+        self.max_len = max_len;
+        self
+    }
+}
+
+impl Default for Varint {
+    fn default() -> Varint { Varint::new() }
+}
+
+impl Decoder for Varint {
+    fn decode(&self, buf: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+        // Ten bytes of seven bits each cover every u64.
+        const MAX_VARINT_LEN: usize = 10;
+
+        let mut len: u64 = 0;
+        let mut prefix_len = 0;
+        loop {
+            if prefix_len == MAX_VARINT_LEN {
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed varint"));
+            }
+            let b = match buf.get(prefix_len) {
+                Some(&b) => b,
+                None => return Ok(None)
+            };
+            // Only the lowest bit of the last byte still fits in a u64.
+            if prefix_len == MAX_VARINT_LEN - 1 && b > 1 {
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed varint"));
+            }
+
+            len |= ((b & 0x7f) as u64) << (7 * prefix_len);
+            prefix_len += 1;
+            if len > self.max_len as u64 { return Err(too_long()); }
+            if b & 0x80 == 0 { break; }
+        }
+
+        // With a max_len near usize::MAX, the length alone can leave no
+        // room for the prefix.
+        let end = match prefix_len.checked_add(len as usize) {
+            Some(end) => end,
+            None => return Err(too_long())
+        };
+        if buf.len() < end { return Ok(None); }
+
+        Ok(Some((buf[prefix_len..end].to_vec(), end)))
+    }
+}
+
+impl Encoder for Varint {
+    fn encode(&self, frame: &[u8], dst: &mut Vec<u8>) -> io::Result<()> {
+        if frame.len() > self.max_len { return Err(too_long()); }
+
+        let mut len = frame.len() as u64;
+        while len >= 0x80 {
+            dst.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        dst.push(len as u8);
+
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+fn too_long() -> Error {
+    Error::new(ErrorKind::InvalidData, "Frame exceeds maximum length")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{Decoder, Encoder, Endian, LengthPrefixed, Lines, PrefixWidth, Varint};
+
+
+    fn encode<E: Encoder>(codec: &E, frame: &[u8]) -> Vec<u8> {
+        let mut dst = Vec::new();
+        codec.encode(frame, &mut dst).unwrap();
+        dst
+    }
+
+    fn assert_invalid_data<D: Decoder>(codec: &D, buf: &[u8]) {
+        assert_eq!(codec.decode(buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    /// Every proper prefix of `buf` is incomplete, and `buf` itself decodes
+    /// to `frame`.
+    fn assert_decodes<D: Decoder>(codec: &D, buf: &[u8], frame: &[u8]) {
+        for n in 0..buf.len() {
+            assert_eq!(codec.decode(&buf[..n]).unwrap(), None, "{} bytes", n);
+        }
+
+        let mut with_next = buf.to_vec();
+        with_next.extend_from_slice(b"next");
+        assert_eq!(codec.decode(&with_next).unwrap(), Some((frame.to_vec(), buf.len())));
+    }
+
+    #[test]
+    fn lines_partial_frames() {
+        let lines = Lines::new();
+        assert_decodes(&lines, b"hello\n", b"hello");
+        assert_decodes(&lines, b"hello\r\n", b"hello");
+        assert_decodes(&lines, b"\n", b"");
+    }
+
+    #[test]
+    fn lines_without_delimiter() {
+        let lines = Lines::new().max_len(4);
+        assert_eq!(lines.decode(b"abcd").unwrap(), None);
+        assert_eq!(lines.decode(b"abcd\r").unwrap(), None);
+        assert_invalid_data(&lines, b"abcdef");
+    }
+
+    #[test]
+    fn lines_max_len() {
+        let lines = Lines::new().max_len(4);
+        assert_eq!(lines.decode(b"abcd\r\n").unwrap(), Some((b"abcd".to_vec(), 6)));
+        assert_invalid_data(&lines, b"abcde\n");
+        assert_eq!(lines.encode(b"abcde", &mut Vec::new()).unwrap_err().kind(),
+                   ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn length_prefixed_byte_order() {
+        let frame = vec![7u8; 0x0102];
+        for &(width, endian, prefix) in &[
+            (PrefixWidth::U16, Endian::Big, &[0x01, 0x02][..]),
+            (PrefixWidth::U16, Endian::Little, &[0x02, 0x01][..]),
+            (PrefixWidth::U32, Endian::Big, &[0, 0, 0x01, 0x02][..]),
+            (PrefixWidth::U32, Endian::Little, &[0x02, 0x01, 0, 0][..])
+        ] {
+            let codec = LengthPrefixed::new(width, endian);
+            let buf = encode(&codec, &frame);
+            assert_eq!(&buf[..prefix.len()], prefix);
+            assert_decodes(&codec, &buf, &frame);
+        }
+    }
+
+    #[test]
+    fn length_prefixed_max_len() {
+        let codec = LengthPrefixed::new(PrefixWidth::U16, Endian::Big).max_len(4);
+        assert_eq!(codec.decode(&[0, 4]).unwrap(), None);
+        // Rejected from the prefix alone, before the frame arrives.
+        assert_invalid_data(&codec, &[0, 5]);
+        assert!(codec.encode(b"abcde", &mut Vec::new()).is_err());
+
+        // Capped at what the prefix can express.
+        let codec = LengthPrefixed::new(PrefixWidth::U16, Endian::Big).max_len(1 << 20);
+        assert!(codec.encode(&vec![0u8; 1 << 16], &mut Vec::new()).is_err());
+        assert_eq!(codec.decode(&[0xff, 0xff]).unwrap(), None);
+    }
+
+    #[test]
+    fn varint_partial_frames() {
+        let codec = Varint::new();
+        let frame = vec![1u8; 300];
+        let buf = encode(&codec, &frame);
+        assert_eq!(&buf[..2], &[0xac, 0x02]);
+        assert_decodes(&codec, &buf, &frame);
+        assert_decodes(&codec, &[0], b"");
+    }
+
+    #[test]
+    fn varint_max_len() {
+        let codec = Varint::new().max_len(300);
+        assert_eq!(codec.decode(&[0xac, 0x02]).unwrap(), None);
+        assert_invalid_data(&codec, &[0xad, 0x02]);
+        // Rejected as soon as the bytes so far exceed it.
+        assert_invalid_data(&codec, &[0x80, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let codec = Varint::new().max_len(usize::MAX);
+
+        // Ten bytes are enough for any u64, an eleventh is never read.
+        let mut buf = vec![0x80u8; 10];
+        assert_invalid_data(&codec, &buf);
+        buf.truncate(9);
+        assert_eq!(codec.decode(&buf).unwrap(), None);
+
+        // Bits past the 64th in the tenth byte.
+        let mut buf = vec![0x80u8; 9];
+        buf.push(0x02);
+        assert_invalid_data(&codec, &buf);
+
+        // u64::MAX itself, which leaves no room for the prefix.
+        let mut buf = vec![0xffu8; 9];
+        buf.push(0x01);
+        assert_invalid_data(&codec, &buf);
+    }
+}
@@ -5,51 +5,1364 @@
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
 
 
-use std::io;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, IoSlice};
 use std::net::SocketAddr;
 use std::os::unix::io::RawFd;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use event_loop;
+use parking_lot::{Mutex, MutexGuard};
+#[cfg(feature = "ssl")]
+use openssl::ssl::{NameType, SslRef};
+#[cfg(feature = "ssl")]
+use openssl::x509::X509;
+
+use buf::{Buffer, Shrink};
+use codec::Codec;
+use event_loop::{EventLoop, LoopHandle};
+use pipe::Pipe;
 use socket;
+use timer::TimerId;
+use zerocopy::ZeroCopy;
+
+pub use self::plain::TcpTransport;
+#[cfg(feature = "ssl")]
+pub use self::ssl::{SubjectAltName, TlsTransport};
+pub use self::unix::UnixTransport;
+
 
+mod plain;
+#[cfg(feature = "ssl")]
+mod ssl;
+mod unix;
 
-#[derive(Debug, Clone, Copy)]
+
+/// Handle to a connection managed by an event loop.
+///
+/// Handles are cheap to clone, every clone refers to the same socket
+/// and buffers.
+#[derive(Clone)]
 pub struct Connection {
     pub socket: RawFd,
-    pub addr: SocketAddr
+    /// Address of the peer. Unix-domain peers have none, theirs is the
+    /// unspecified address `0.0.0.0:0`.
+    pub addr: SocketAddr,
+    inner: Arc<Inner>
+}
+
+/// A kind of stream carried over a connection's socket, moving bytes
+/// between the socket and the connection's buffers.
+///
+/// Reads only ever happen on the thread of the event loop the connection
+/// belongs to, and writes with the connection's flushing lock held. The
+/// socket belongs to the connection, a transport never closes it.
+pub trait Transport: Send {
+    /// Returns the socket the stream is carried over.
+    fn fd(&self) -> RawFd;
+
+    /// Reads everything available into `rx_buf` until EAGAIN/EWOULDBLOCK
+    /// is received, or at least `limit` bytes have been read. Fails with
+    /// `UnexpectedEof` once the peer has closed its side.
+    fn recv(&mut self, rx_buf: &Buffer, limit: usize) -> io::Result<usize>;
+
+    /// Writes everything in `tx_buf` until EAGAIN/EWOULDBLOCK is received,
+    /// removing whatever was sent. Shared segments may be sent with
+    /// `zerocopy`, if the transport supports it.
+    ///
+    /// Returns the number of bytes sent, and whether anything is left in
+    /// the buffer.
+    fn send(&mut self,
+            tx_buf: &Buffer,
+            zerocopy: Option<&mut ZeroCopy>) -> io::Result<(usize, bool)>;
+
+    /// Shuts down the transmit side, the peer receives EOF once everything
+    /// already sent has been delivered.
+    fn shutdown_write(&mut self) -> io::Result<()>;
+
+    /// Shuts down both sides, right before the socket is closed.
+    fn shutdown(&mut self) -> io::Result<()>;
+
+    /// Sets or clears `TCP_CORK`, if the socket has it.
+    fn set_cork(&mut self, _cork: bool) -> io::Result<()> { Ok(()) }
+
+    /// Returns true while a handshake is in progress, the handler is only
+    /// told about the connection once it has completed.
+    fn is_handshaking(&self) -> bool { false }
+
+    /// Returns true if the handshake in progress is waiting for the socket
+    /// to become writable, rather than readable.
+    fn handshake_wants_write(&self) -> bool { false }
+
+    /// Drives the handshake as far as the socket allows, returning true
+    /// once it has completed.
+    fn handshake(&mut self) -> io::Result<bool> { Ok(true) }
+
+    /// Returns true if the stream is encrypted with TLS. The bytes on the
+    /// socket are then not the connection's own, and cannot be spliced.
+    fn is_tls(&self) -> bool { false }
+
+    /// Returns true if shared segments can be sent with `MSG_ZEROCOPY`.
+    fn supports_zerocopy(&self) -> bool { false }
+
+    /// Returns the TLS session the stream is encrypted with, if any.
+    #[cfg(feature = "ssl")]
+    fn ssl(&self) -> Option<&SslRef> { None }
+}
+
+/// State shared between every handle to a connection.
+struct Inner {
+    rx_buf: Buffer,
+    /// Delimiter `read_until` last looked for, and how many bytes at the
+    /// front of the receive buffer it knows not to contain it. Taken before
+    /// the receive buffer, and forgotten whenever bytes are removed from it.
+    rx_scanned: Mutex<Option<(u8, usize)>>,
+    tx_buf: Buffer,
+    state: Mutex<State>,
+    timing: Mutex<Timing>,
+    reading: Mutex<Reading>,
+    writing: Mutex<Writing>,
+    splicing: Mutex<Splicing>,
+    zerocopy: Mutex<Option<ZeroCopy>>,
+    codec: Mutex<Option<Arc<dyn Codec>>>,
+    /// Stream carried over the socket. Taken after the flushing lock.
+    transport: Mutex<Box<dyn Transport>>,
+    /// Held while the connection's epoll interest is computed and set, so
+    /// that the last update always reflects the latest state.
+    interest: Mutex<()>,
+    /// Held while writing to the socket, so that only one thread at a time
+    /// sends from the transmit buffer and the socket is not closed from
+    /// underneath a write.
+    flushing: Mutex<()>,
+    ev_loop: Weak<EventLoop>
+}
+
+/// Settings applied to every new connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    pub timeouts: Timeouts,
+    pub rx_high_watermark: Option<usize>,
+    pub tx_high_watermark: Option<usize>,
+    pub tx_low_watermark: usize,
+    pub tx_policy: TxPolicy,
+    pub shrink: Shrink,
+    /// Cork every connection while `Handler::on_recv` runs.
+    pub cork_on_recv: bool,
+    /// Set `TCP_CORK` on the socket while a connection is corked.
+    pub tcp_cork: bool,
+    /// Send shared segments of at least this many bytes zero-copy.
+    pub zerocopy: Option<usize>
+}
+
+/// What `Connection::send` does with data that would take the transmit
+/// buffer past its high watermark.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TxPolicy {
+    /// The data is not buffered and `send` fails with `WouldBlock`. Data
+    /// is always accepted into an empty buffer, so a single write larger
+    /// than the high watermark can still be sent.
+    #[default]
+    Reject,
+    /// The connection is shutdown and `send` fails with `BrokenPipe`.
+    Close,
+    /// The data is buffered anyway.
+    Accept
+}
+
+/// Where a connection is in its lifetime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Reading and writing normally.
+    Open,
+    /// No longer reading, waiting for the transmit buffer to be sent.
+    Flushing,
+    /// Transmit side shutdown, waiting for the peer to close its side.
+    Lingering,
+    /// Socket has been closed.
+    Closed
+}
+
+/// The timeout that expired for a connection.
+///
+/// Passed to `Handler::on_error` as the inner error of an `io::Error` of
+/// kind `TimedOut`, and can be retrieved with `io::Error::get_ref` and
+/// `downcast_ref`. The connection is shutdown right after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    /// Nothing was sent or received within the idle timeout.
+    Idle,
+    /// Nothing was received within the read timeout.
+    Read,
+    /// The transmit buffer made no progress within the write timeout.
+    Write
+}
+
+/// Timeouts applied to a connection, `None` disables that timeout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    pub idle: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>
+}
+
+/// Why a connection's timer has expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Timeout(Timeout),
+    /// The deadline given to `close_after_flush` has passed.
+    Drain
+}
+
+/// Whether more data should be read from the socket.
+struct Reading {
+    paused: bool,
+    high_watermark: Option<usize>
+}
+
+/// Transmit buffer limits, and whether the handler has been told the
+/// buffer went past its high watermark.
+struct Writing {
+    high_watermark: Option<usize>,
+    low_watermark: usize,
+    policy: TxPolicy,
+    /// Went past the high watermark and has not drained since.
+    above: bool,
+    /// `above` has not yet been reported to the handler.
+    unreported: bool,
+    /// Number of calls to `cork` not yet matched by `uncork`.
+    corked: usize,
+    tcp_cork: bool
+}
+
+/// Connections this one is spliced to and from, and the pipe in between.
+#[derive(Default)]
+struct Splicing {
+    to: Option<(Connection, Arc<Pipe>)>,
+    from: Option<(Connection, Arc<Pipe>)>
+}
+
+/// Activity used to decide when a connection's timeouts expire.
+///
+/// Activity is recorded without touching the connection's timer, which only
+/// ever fires early. When it does, the timer is rescheduled for whatever
+/// deadline is now the nearest.
+struct Timing {
+    timeouts: Timeouts,
+    last_recv: Instant,
+    last_send: Instant,
+    /// Last time the transmit buffer was drained from, or became non-empty.
+    write_progress: Instant,
+    drain_deadline: Option<Instant>,
+    timer: Option<(TimerId, Instant)>
+}
+
+impl Timing {
+    fn new(timeouts: Timeouts) -> Timing {
+        let now = Instant::now();
+        Timing {
+            timeouts,
+            last_recv: now,
+            last_send: now,
+            write_progress: now,
+            drain_deadline: None,
+            timer: None
+        }
+    }
+
+    /// Returns the nearest deadline and what expires at it.
+    fn next_expiry(&self, tx_pending: bool) -> Option<(Instant, Expiry)> {
+        let last_activity = if self.last_recv > self.last_send {
+            self.last_recv
+        } else {
+            self.last_send
+        };
+
+        let mut deadlines = Vec::with_capacity(4);
+        if let Some(d) = self.drain_deadline {
+            deadlines.push((d, Expiry::Drain));
+        }
+        if let Some(d) = self.timeouts.write {
+            if tx_pending {
+                deadlines.push((self.write_progress + d, Expiry::Timeout(Timeout::Write)));
+            }
+        }
+        if let Some(d) = self.timeouts.read {
+            deadlines.push((self.last_recv + d, Expiry::Timeout(Timeout::Read)));
+        }
+        if let Some(d) = self.timeouts.idle {
+            deadlines.push((last_activity + d, Expiry::Timeout(Timeout::Idle)));
+        }
+
+        deadlines.into_iter().min_by_key(|&(at, _)| at)
+    }
 }
 
 impl Connection {
-    /// Creates a new Connection.
-    pub fn new(socket: RawFd, addr: SocketAddr) -> Connection {
-        Connection { socket: socket, addr: addr }
+    /// Creates a new Connection owned by `ev_loop`.
+    pub(crate) fn new(transport: Box<dyn Transport>,
+                      addr: SocketAddr,
+                      config: Config,
+                      ev_loop: Weak<EventLoop>) -> Connection
+    {
+        Connection {
+            socket: transport.fd(),
+            addr,
+            inner: Arc::new(Inner {
+                rx_buf: Buffer::with_shrink(config.shrink),
+                rx_scanned: Mutex::new(None),
+                tx_buf: Buffer::with_shrink(config.shrink),
+                state: Mutex::new(State::Open),
+                timing: Mutex::new(Timing::new(config.timeouts)),
+                reading: Mutex::new(Reading {
+                    paused: false,
+                    high_watermark: config.rx_high_watermark
+                }),
+                writing: Mutex::new(Writing {
+                    high_watermark: config.tx_high_watermark,
+                    low_watermark: config.tx_low_watermark,
+                    policy: config.tx_policy,
+                    above: false,
+                    unreported: false,
+                    corked: 0,
+                    tcp_cork: config.tcp_cork
+                }),
+                splicing: Mutex::new(Splicing::default()),
+                zerocopy: Mutex::new(None),
+                codec: Mutex::new(None),
+                transport: Mutex::new(transport),
+                interest: Mutex::new(()),
+                flushing: Mutex::new(()),
+                ev_loop
+            })
+        }
     }
 
     /// Returns the current number of bytes in this connection's
     /// receive buffer.
     pub fn bytes_avail(&self) -> io::Result<usize> {
-        socket::peek(self.socket)
+        Ok(self.inner.rx_buf.len())
     }
 
     /// Removes up to `buf.len()` bytes from this connection's receive buffer
     /// and copies them into `buf` returning the total amount copied.
+    ///
+    /// Reading from the socket resumes once the receive buffer drops back
+    /// below its high watermark.
     pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
-        socket::take(self.socket, buf)
+        self.consume_rx(|rx_buf| rx_buf.take(buf))
+    }
+
+    /// Copies up to `buf.len()` bytes from the front of this connection's
+    /// receive buffer into `buf` without removing them, returning the total
+    /// amount copied.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.inner.rx_buf.peek(buf))
+    }
+
+    /// Calls `f` with everything in this connection's receive buffer, in
+    /// place, and removes however many bytes from the front `f` returns it
+    /// has consumed, returning that amount.
+    ///
+    /// Parsers can work straight out of the receive buffer this way,
+    /// returning 0 until a complete message has been received. Should the
+    /// buffered bytes wrap around the end of the receive buffer's storage,
+    /// they are moved to the start of it first.
+    ///
+    /// The receive buffer stays locked while `f` runs, so `f` must not call
+    /// any of this connection's other receive methods, such as `recv`,
+    /// `peek`, `bytes_avail` or `read_until`, which would deadlock.
+    pub fn with_rx<F>(&self, f: F) -> io::Result<usize> where F: FnOnce(&[u8]) -> usize {
+        self.consume_rx(|rx_buf| rx_buf.consume_with(f))
+    }
+
+    /// Fills `buf` from the front of this connection's receive buffer if it
+    /// holds at least `buf.len()` bytes, returning whether it did. Nothing
+    /// is removed otherwise.
+    pub fn read_exact(&self, buf: &mut [u8]) -> io::Result<bool> {
+        self.consume_rx(|rx_buf| rx_buf.take_exact(buf))
+    }
+
+    /// Removes everything up to and including the first `delim` from this
+    /// connection's receive buffer and returns it. Returns `None`, removing
+    /// nothing, if `delim` has not been received yet.
+    ///
+    /// Bytes already searched for `delim` are not searched again by the
+    /// next call, as long as nothing is removed from the receive buffer in
+    /// between.
+    pub fn read_until(&self, delim: u8) -> io::Result<Option<Vec<u8>>> {
+        let mut unit = None;
+        self.consume_rx_scanned(|rx_buf, scanned| {
+            let start = match *scanned {
+                Some((d, len)) if d == delim => len,
+                _ => 0
+            };
+            rx_buf.consume_with(|rx| match rx[start..].iter().position(|&b| b == delim) {
+                Some(pos) => {
+                    *scanned = None;
+                    unit = Some(rx[..start + pos + 1].to_vec());
+                    start + pos + 1
+                }
+                None => {
+                    *scanned = Some((delim, rx.len()));
+                    0
+                }
+            })
+        })?;
+        Ok(unit)
+    }
+
+    /// Sets the codec frames are decoded from and encoded to this
+    /// connection with, replacing its server's codec. With `None`, raw
+    /// bytes are delivered to `Handler::on_recv` again.
+    pub fn set_codec(&self, codec: Option<Arc<dyn Codec>>) {
+        *self.inner.codec.lock() = codec;
+    }
+
+    /// Returns true if this connection's bytes are encrypted with TLS.
+    pub fn is_tls(&self) -> bool { self.inner.transport.lock().is_tls() }
+
+    /// Returns the DER encoded certificate the peer presented during the
+    /// TLS handshake, if any. Clients are only asked for one when the
+    /// server was configured with `SslConfig::verify_client`, and a
+    /// certificate returned from `Handler::on_connect` onward has been
+    /// verified.
+    #[cfg(feature = "ssl")]
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.peer_x509().and_then(|cert| cert.to_der().ok())
+    }
+
+    /// Returns the subject of the peer's certificate as comma separated
+    /// `name=value` pairs, such as `C=US, O=Example, CN=client`.
+    #[cfg(feature = "ssl")]
+    pub fn peer_subject(&self) -> Option<String> {
+        self.peer_x509().map(|cert| ssl::subject(&cert))
+    }
+
+    /// Returns the names in the subject alternative name extension of the
+    /// peer's certificate, empty if it presented none.
+    #[cfg(feature = "ssl")]
+    pub fn peer_san(&self) -> Vec<SubjectAltName> {
+        match self.peer_x509() {
+            Some(cert) => ssl::subject_alt_names(&cert),
+            None => Vec::new()
+        }
+    }
+
+    /// Returns the host name the client asked for through server name
+    /// indication during the TLS handshake, if any, see
+    /// `SslConfig::server_name`.
+    #[cfg(feature = "ssl")]
+    pub fn sni_hostname(&self) -> Option<String> {
+        let transport = self.inner.transport.lock();
+        transport.ssl()
+            .and_then(|ssl| ssl.servername(NameType::HOST_NAME))
+            .map(|name| name.to_owned())
+    }
+
+    /// Returns the application protocol selected with ALPN during the TLS
+    /// handshake, if any, see `SslConfig::alpn_protocols`.
+    #[cfg(feature = "ssl")]
+    pub fn alpn_protocol(&self) -> Option<String> {
+        self.with_alpn_protocol(|p| p.map(|p| String::from_utf8_lossy(p).into_owned()))
+    }
+
+    /// Returns the codec frames are decoded from and encoded to this
+    /// connection with.
+    pub fn codec(&self) -> Option<Arc<dyn Codec>> {
+        self.inner.codec.lock().clone()
+    }
+
+    /// Removes the next complete frame from this connection's receive
+    /// buffer and decodes it, returning `None` if there is none yet.
+    ///
+    /// The event loop does this for every connection that has a codec,
+    /// calling `Handler::on_message` with each frame. Fails with
+    /// `InvalidInput` if this connection has no codec. Bytes the codec
+    /// fails to decode are left in the receive buffer.
+    pub fn recv_frame(&self) -> io::Result<Option<Vec<u8>>> {
+        let codec = self.codec().ok_or_else(no_codec)?;
+
+        let mut r = Ok(None);
+        self.with_rx(|rx| match codec.decode(rx) {
+            Ok(Some((frame, used))) => {
+                r = Ok(Some(frame));
+                used
+            }
+            Ok(None) => 0,
+            Err(err) => {
+                r = Err(err);
+                0
+            }
+        })?;
+        r
+    }
+
+    /// Stops reading from the socket until `resume_reading` is called.
+    ///
+    /// Data the peer sends is left in the kernel's socket buffer, and once
+    /// that fills up TCP flow control stops the peer from sending more.
+    pub fn pause_reading(&self) -> io::Result<()> {
+        self.inner.reading.lock().paused = true;
+        self.interest_changed()
+    }
+
+    /// Resumes reading from the socket after a call to `pause_reading`.
+    pub fn resume_reading(&self) -> io::Result<()> {
+        self.inner.reading.lock().paused = false;
+        self.interest_changed()
+    }
+
+    /// Returns true if reading has been paused with `pause_reading`.
+    pub fn is_reading_paused(&self) -> bool {
+        self.inner.reading.lock().paused
+    }
+
+    /// Sets the receive buffer's high watermark, reading from the socket
+    /// stops while the receive buffer holds at least `bytes` bytes. `None`
+    /// lets the receive buffer grow without bound.
+    pub fn set_rx_high_watermark(&self, bytes: Option<usize>) -> io::Result<()> {
+        self.inner.reading.lock().high_watermark = bytes;
+        self.interest_changed()
+    }
+
+    /// Returns the receive buffer's high watermark.
+    pub fn rx_high_watermark(&self) -> Option<usize> {
+        self.inner.reading.lock().high_watermark
     }
 
     /// Copies `buf` into this connection's transmit buffer.
+    ///
+    /// If the transmit buffer was empty, as much as the socket accepts is
+    /// written right away, and only the rest waits for the event loop.
+    ///
+    /// Fails with `BrokenPipe` once the connection has started closing. If
+    /// `buf` would take the transmit buffer past its high watermark, the
+    /// connection's `TxPolicy` decides what happens.
     pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
-        socket::add_to_tx_buf(self.socket, buf)
+        self.queue(buf.len(), |tx_buf| tx_buf.append(buf))
+    }
+
+    /// Copies each of `bufs` into this connection's transmit buffer in order,
+    /// such as a header followed by a body, returning the total copied.
+    ///
+    /// Nothing sent concurrently ends up in between them, and the transmit
+    /// buffer's limits apply to their total. Otherwise behaves the same as
+    /// `send`.
+    pub fn send_vectored(&self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        self.queue(len, |tx_buf| tx_buf.append_vectored(bufs))
+    }
+
+    /// Queues `buf` in this connection's transmit buffer by reference.
+    ///
+    /// The same payload can be queued on any number of connections, it is
+    /// written straight from `buf` and only freed once every connection has
+    /// sent it. Large payloads can be sent without the kernel copying them
+    /// either, see `set_zerocopy`. Otherwise behaves the same as `send`.
+    pub fn send_shared(&self, buf: Arc<[u8]>) -> io::Result<usize> {
+        let len = buf.len();
+        self.queue(len, move |tx_buf| tx_buf.append_shared(buf))
+    }
+
+    /// Queues `len` bytes of `file`, starting at `offset`, in this
+    /// connection's transmit buffer, returning `len`.
+    ///
+    /// The region is written with sendfile, straight from the page cache,
+    /// in order with everything sent before and after it. Once all of it
+    /// has been sent, `file` is handed back through
+    /// `Handler::on_file_sent`. Fails with `InvalidInput` if `len` is 0,
+    /// otherwise behaves the same as `send`.
+    pub fn send_file(&self, file: File, offset: u64, len: usize) -> io::Result<usize> {
+        if len == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Empty file region"));
+        }
+        self.queue(len, move |tx_buf| tx_buf.append_file(file, offset, len))
+    }
+
+    /// Sends everything received on this connection from now on straight to
+    /// `other`, through a kernel pipe, without it ever being copied into
+    /// userspace or passed to `Handler::on_recv`.
+    ///
+    /// Anything already in the receive buffer is sent to `other` first, so
+    /// this should be called from this connection's event loop thread, such
+    /// as from `on_connect` or `on_recv`. Reading pauses while `other`
+    /// cannot keep up, and resumes once the pipe has drained to it. Splicing
+    /// stops when either connection is shutdown, EOF is still reported to
+    /// `Handler::on_error` as usual.
+    ///
+    /// A connection can splice to one other connection at a time, and have
+    /// one connection splice to it. Call it on both to relay in both
+    /// directions.
+    pub fn splice_to(&self, other: &Connection) -> io::Result<()> {
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Connection cannot splice to itself"));
+        }
+        if self.state() != State::Open || other.state() != State::Open {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Connection closing"));
+        }
+        if self.is_tls() || other.is_tls() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "TLS connections cannot be spliced"));
+        }
+
+        let pipe = Arc::new(Pipe::new()?);
+        {
+            let (mut source, mut target) = lock_splicing(self, other);
+            if source.to.is_some() {
+                return Err(Error::new(ErrorKind::AlreadyExists,
+                                      "Connection already spliced"));
+            }
+            if target.from.is_some() {
+                return Err(Error::new(ErrorKind::AlreadyExists,
+                                      "Connection already spliced to"));
+            }
+            source.to = Some((other.clone(), pipe.clone()));
+            target.from = Some((self.clone(), pipe));
+        }
+
+        let pending = self.inner.rx_buf.len();
+        if pending > 0 {
+            let mut buf = vec![0u8; pending];
+            let taken = self.consume_rx(|rx_buf| rx_buf.take(&mut buf))?;
+            other.inner.tx_buf.append(&buf[..taken]);
+            other.push()?;
+        }
+
+        self.interest_changed()
+    }
+
+    /// Sends payloads queued with `send_shared` that have at least `min_len`
+    /// bytes left to send with `MSG_ZEROCOPY`, or stops if `None`.
+    ///
+    /// The kernel then transmits straight out of the payload instead of
+    /// copying it, which only pays off for payloads of many kilobytes. Each
+    /// payload is kept alive until the kernel reports it is done with it,
+    /// even after it has been removed from the transmit buffer, see
+    /// `zerocopy_pending`. Fails with `InvalidInput` on TLS and Unix-domain
+    /// connections, or if the kernel does not support `SO_ZEROCOPY`.
+    pub fn set_zerocopy(&self, min_len: Option<usize>) -> io::Result<()> {
+        if min_len.is_some() && !self.supports_zerocopy() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Transport cannot send zero-copy"));
+        }
+
+        let mut zerocopy = self.inner.zerocopy.lock();
+        match (zerocopy.as_mut(), min_len) {
+            (Some(zc), _) => zc.set_min_len(min_len),
+            (None, Some(min_len)) => {
+                *zerocopy = Some(ZeroCopy::enable(self.socket, min_len)?);
+            }
+            (None, None) => { }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of zero-copy sends the kernel has yet to report
+    /// as complete, their payloads are released once it has.
+    pub fn zerocopy_pending(&self) -> usize {
+        match *self.inner.zerocopy.lock() {
+            Some(ref zc) => zc.in_flight(),
+            None => 0
+        }
+    }
+
+    /// Encodes `frame` with this connection's codec and queues the result in
+    /// its transmit buffer, returning the encoded length.
+    ///
+    /// Fails with `InvalidInput` if this connection has no codec, otherwise
+    /// behaves the same as `send`.
+    pub fn send_frame(&self, frame: &[u8]) -> io::Result<usize> {
+        let codec = self.codec().ok_or_else(no_codec)?;
+        let mut buf = Vec::with_capacity(frame.len() + 8);
+        codec.encode(frame, &mut buf)?;
+        self.send(&buf)
+    }
+
+    /// Sets the transmit buffer's high watermark, see `TxPolicy`. `None`
+    /// lets the transmit buffer grow without bound.
+    pub fn set_tx_high_watermark(&self, bytes: Option<usize>) {
+        self.inner.writing.lock().high_watermark = bytes;
+    }
+
+    /// Sets the transmit buffer's low watermark, `Handler::on_tx_drained`
+    /// is called once a transmit buffer that went past its high watermark
+    /// has drained down to `bytes`.
+    pub fn set_tx_low_watermark(&self, bytes: usize) {
+        self.inner.writing.lock().low_watermark = bytes;
+    }
+
+    /// Sets what happens to data that would take the transmit buffer past
+    /// its high watermark.
+    pub fn set_tx_policy(&self, policy: TxPolicy) {
+        self.inner.writing.lock().policy = policy;
+    }
+
+    /// Returns the transmit buffer's high watermark.
+    pub fn tx_high_watermark(&self) -> Option<usize> {
+        self.inner.writing.lock().high_watermark
+    }
+
+    /// Returns the number of bytes waiting in the transmit buffer.
+    pub fn tx_pending(&self) -> usize { self.inner.tx_buf.len() }
+
+    /// Holds back everything sent to this connection until the matching
+    /// call to `uncork`, so that many small sends leave in one write.
+    ///
+    /// Calls nest, the transmit buffer is only written once every `cork`
+    /// has been matched by an `uncork`. If the connection was configured
+    /// with `tcp_cork`, `TCP_CORK` is also set on the socket so the kernel
+    /// only sends full sized packets until uncorked.
+    pub fn cork(&self) -> io::Result<()> {
+        let mut writing = self.inner.writing.lock();
+        writing.corked += 1;
+        if writing.corked == 1 && writing.tcp_cork {
+            self.inner.transport.lock().set_cork(true)?;
+        }
+        Ok(())
+    }
+
+    /// Undoes a call to `cork`. Once every `cork` has been matched, as much
+    /// of the transmit buffer as the socket accepts is written right away,
+    /// and only the rest waits for the event loop.
+    pub fn uncork(&self) -> io::Result<()> {
+        let tcp_cork = {
+            let mut writing = self.inner.writing.lock();
+            if writing.corked == 0 { return Ok(()); }
+            writing.corked -= 1;
+            if writing.corked > 0 { return Ok(()); }
+            writing.tcp_cork
+        };
+
+        let ev_loop = self.ev_loop()?;
+        if self.state() == State::Open && self.inner.tx_buf.len() > 0 {
+            self.write_through(&ev_loop)?;
+        }
+
+        // Clearing TCP_CORK sends whatever partial packet is left.
+        if tcp_cork && self.state() != State::Closed {
+            self.inner.transport.lock().set_cork(false)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if this connection has been corked with `cork`.
+    pub fn is_corked(&self) -> bool { self.inner.writing.lock().corked > 0 }
+
+    /// Sets the idle timeout, the connection times out if nothing is sent
+    /// or received for `timeout`.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.update_timeouts(|t| t.idle = timeout)
+    }
+
+    /// Sets the read timeout, the connection times out if nothing is
+    /// received for `timeout`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.update_timeouts(|t| t.read = timeout)
+    }
+
+    /// Sets the write timeout, the connection times out if its transmit
+    /// buffer is not empty and nothing could be sent for `timeout`.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.update_timeouts(|t| t.write = timeout)
+    }
+
+    /// Returns the timeouts currently applied to this connection.
+    pub fn timeouts(&self) -> Timeouts { self.inner.timing.lock().timeouts }
+
+    /// Returns a handle to the event loop this connection belongs to, timers
+    /// scheduled on it run on the same thread as this connection's callbacks.
+    pub fn event_loop(&self) -> LoopHandle {
+        LoopHandle::new(self.inner.ev_loop.clone())
+    }
+
+    /// Gracefully closes this connection.
+    ///
+    /// Reading stops immediately, and once everything in the transmit
+    /// buffer has been sent the transmit side is shutdown, so the peer
+    /// receives every byte followed by EOF. The socket is then closed when
+    /// the peer closes its side. If any of this has not happened within
+    /// `timeout`, the connection is shutdown regardless.
+    pub fn close_after_flush(&self, timeout: Duration) -> io::Result<()> {
+        let ev_loop = self.ev_loop()?;
+        {
+            let mut state = self.inner.state.lock();
+            if *state != State::Open { return Ok(()); }
+            *state = State::Flushing;
+        }
+
+        self.inner.timing.lock().drain_deadline = Some(Instant::now() + timeout);
+        ev_loop.drain_conn(self)
     }
 
     /// Shuts down further transport for this socket, and
     /// informs the remote socket of disconnect.
     pub fn shutdown(&self) -> io::Result<()> {
-        let _ = event_loop::del_conn(*self);
-        let _ = socket::shutdown(self.socket);
-        socket::close(self.socket)
+        // Only the first shutdown of a connection closes the socket,
+        // later calls could otherwise close an fd that has since been
+        // handed out to a new connection.
+        {
+            let mut state = self.inner.state.lock();
+            if *state == State::Closed { return Ok(()); }
+            *state = State::Closed;
+        }
+
+        let ev_loop = self.inner.ev_loop.upgrade();
+        if let Some(ref ev_loop) = ev_loop {
+            let _ = ev_loop.del_conn(self);
+        }
+        self.unsplice();
+
+        // Waits out a write in progress on another thread.
+        let _flushing = self.inner.flushing.lock();
+        let _ = self.inner.transport.lock().shutdown();
+        let r = socket::close(self.socket);
+
+        if let Some(ref ev_loop) = ev_loop {
+            ev_loop.on_close(self);
+        }
+
+        r
+    }
+
+    pub(crate) fn state(&self) -> State { *self.inner.state.lock() }
+
+    /// Closes the socket without telling the handler, for connections it
+    /// never heard of or can no longer be told about. Like `shutdown`, only
+    /// the first close of a connection closes its socket.
+    pub(crate) fn abandon(&self) {
+        {
+            let mut state = self.inner.state.lock();
+            if *state == State::Closed { return; }
+            *state = State::Closed;
+        }
+
+        self.unsplice();
+        let _flushing = self.inner.flushing.lock();
+        let _ = socket::close(self.socket);
+    }
+
+    /// Returns true while the transport's handshake is in progress, see
+    /// `Transport::is_handshaking`.
+    pub(crate) fn is_handshaking(&self) -> bool {
+        self.inner.transport.lock().is_handshaking()
+    }
+
+    pub(crate) fn handshake_wants_write(&self) -> bool {
+        self.inner.transport.lock().handshake_wants_write()
+    }
+
+    /// Drives the transport's handshake as far as the socket allows,
+    /// returning true once it has completed.
+    pub(crate) fn handshake(&self) -> io::Result<bool> {
+        let _flushing = self.inner.flushing.lock();
+        self.inner.transport.lock().handshake()
+    }
+
+    /// Reads everything available from the transport into the receive
+    /// buffer, see `Transport::recv`.
+    pub(crate) fn read_socket(&self, limit: usize) -> io::Result<usize> {
+        self.inner.transport.lock().recv(&self.inner.rx_buf, limit)
+    }
+
+    /// Shuts down the transmit side of the transport.
+    pub(crate) fn shutdown_write(&self) -> io::Result<()> {
+        let _flushing = self.inner.flushing.lock();
+        self.inner.transport.lock().shutdown_write()
+    }
+
+    pub(crate) fn supports_zerocopy(&self) -> bool {
+        self.inner.transport.lock().supports_zerocopy()
+    }
+
+    pub(crate) fn set_state(&self, state: State) {
+        *self.inner.state.lock() = state;
+    }
+
+    pub(crate) fn record_recv(&self) {
+        self.inner.timing.lock().last_recv = Instant::now();
+    }
+
+    pub(crate) fn record_send(&self) {
+        let mut timing = self.inner.timing.lock();
+        let now = Instant::now();
+        timing.last_send = now;
+        timing.write_progress = now;
+    }
+
+    /// Returns the nearest deadline of this connection and what expires
+    /// at it.
+    pub(crate) fn next_expiry(&self) -> Option<(Instant, Expiry)> {
+        let tx_pending = self.inner.tx_buf.len() > 0;
+        self.inner.timing.lock().next_expiry(tx_pending)
+    }
+
+    /// Returns the timer currently scheduled for this connection and the
+    /// instant it fires at.
+    pub(crate) fn timer(&self) -> Option<(TimerId, Instant)> {
+        self.inner.timing.lock().timer
+    }
+
+    pub(crate) fn set_timer(&self, timer: Option<(TimerId, Instant)>) {
+        self.inner.timing.lock().timer = timer;
+    }
+
+    /// Returns true if the transmit buffer, or the pipe of a connection
+    /// spliced to this one, should be written to the socket.
+    pub(crate) fn wants_write(&self) -> bool {
+        let spliced = match self.splice_from() {
+            Some(pipe) => pipe.len() > 0,
+            None => false
+        };
+        (self.inner.tx_buf.len() > 0 || spliced) && !self.is_corked()
+    }
+
+    /// Returns true if more data should be read from the socket.
+    pub(crate) fn wants_read(&self) -> bool {
+        // Whatever is spliced to another connection waits in the kernel
+        // until the pipe it goes through has drained.
+        let backlogged = match self.splice_to_target() {
+            Some((_, pipe)) => pipe.len() > 0,
+            None => false
+        };
+        !self.is_reading_paused() && !self.rx_full() && !backlogged
+    }
+
+    /// Returns true if zero-copy sends have ever been enabled, completions
+    /// are then reported on the socket's error queue.
+    pub(crate) fn is_zerocopy(&self) -> bool {
+        self.inner.zerocopy.lock().is_some()
+    }
+
+    /// Releases the payloads of every zero-copy send the kernel has
+    /// completed.
+    pub(crate) fn reap_zerocopy(&self) -> io::Result<usize> {
+        match *self.inner.zerocopy.lock() {
+            Some(ref mut zc) => zc.reap(self.socket),
+            None => Ok(0)
+        }
+    }
+
+    /// Writes out whatever is pending for this connection, including the
+    /// pipe of a connection spliced to it, if it can right now, otherwise
+    /// makes sure its event loop does.
+    pub(crate) fn push(&self) -> io::Result<()> {
+        let ev_loop = self.ev_loop()?;
+        match self.state() {
+            State::Open => self.write_through(&ev_loop),
+            State::Flushing => ev_loop.interest_changed(self),
+            _ => Ok(())
+        }
+    }
+
+    /// Returns the connection this one is spliced to, and the pipe to it.
+    pub(crate) fn splice_to_target(&self) -> Option<(Connection, Arc<Pipe>)> {
+        self.inner.splicing.lock().to.clone()
+    }
+
+    /// Returns the number of bytes that can be read from the socket before
+    /// the receive buffer reaches its high watermark.
+    pub(crate) fn rx_room(&self) -> usize {
+        match self.rx_high_watermark() {
+            Some(hw) => hw.saturating_sub(self.inner.rx_buf.len()),
+            None => usize::MAX
+        }
+    }
+
+    /// Returns whether the transmit buffer went past its high watermark, and
+    /// whether it has since drained to its low watermark, since the last
+    /// call. Both are reported at most once per trip past the watermark.
+    pub(crate) fn tx_watermarks(&self) -> (bool, bool) {
+        let mut writing = self.inner.writing.lock();
+        let high = writing.unreported;
+        writing.unreported = false;
+
+        let drained = writing.above
+            && self.inner.tx_buf.len() <= writing.low_watermark;
+        if drained { writing.above = false; }
+
+        (high, drained)
+    }
+
+    /// Writes as much of the transmit buffer as the socket accepts, see
+    /// `Transport::send`, followed by the pipe of a connection spliced to
+    /// this one.
+    pub(crate) fn flush(&self) -> io::Result<(usize, bool)> {
+        let _flushing = self.inner.flushing.lock();
+        if self.state() == State::Closed { return Ok((0, false)); }
+        self.send_all()
+    }
+
+    pub(crate) fn lock_interest<'a>(&'a self) -> MutexGuard<'a, ()> {
+        self.inner.interest.lock()
+    }
+
+    pub(crate) fn tx_buf(&self) -> &Buffer { &self.inner.tx_buf }
+
+    fn update_timeouts<F>(&self, f: F) -> io::Result<()>
+        where F: FnOnce(&mut Timeouts)
+    {
+        let ev_loop = self.ev_loop()?;
+        f(&mut self.inner.timing.lock().timeouts);
+        ev_loop.schedule_timer(self);
+        Ok(())
+    }
+
+    /// Adds `len` bytes to the transmit buffer with `f`, applying the
+    /// transmit buffer's limits.
+    fn queue<F>(&self, len: usize, f: F) -> io::Result<usize>
+        where F: FnOnce(&Buffer)
+    {
+        let ev_loop = self.ev_loop()?;
+        if self.state() != State::Open {
+            return Err(Error::new(ErrorKind::BrokenPipe, "Connection closing"));
+        }
+
+        let mut write_through = false;
+        let corked;
+        {
+            let mut writing = self.inner.writing.lock();
+            let pending = self.inner.tx_buf.len();
+            let over = match writing.high_watermark {
+                Some(hw) => pending + len > hw,
+                None => false
+            };
+
+            if over {
+                if !writing.above {
+                    writing.above = true;
+                    writing.unreported = true;
+                }
+
+                match writing.policy {
+                    TxPolicy::Reject if pending > 0 => {
+                        drop(writing);
+                        ev_loop.report_tx_progress(self);
+                        return Err(Error::new(ErrorKind::WouldBlock,
+                                              "Transmit buffer full"));
+                    }
+                    TxPolicy::Close => {
+                        drop(writing);
+                        warn!("Transmit buffer limit exceeded for {:?}", self);
+                        let _ = self.shutdown();
+                        return Err(Error::new(ErrorKind::BrokenPipe,
+                                              "Transmit buffer limit exceeded"));
+                    }
+                    _ => { }
+                }
+            }
+
+            if pending == 0 {
+                self.inner.timing.lock().write_progress = Instant::now();
+            }
+
+            f(&self.inner.tx_buf);
+            if pending == 0 { write_through = true; }
+            corked = writing.corked > 0;
+        }
+
+        // Everything queued while corked is written by uncork.
+        if corked {
+            ev_loop.report_tx_progress(self);
+            return Ok(len);
+        }
+
+        // Nothing was queued ahead of this, so there is nothing it could be
+        // reordered with.
+        if write_through {
+            self.write_through(&ev_loop)?;
+        } else {
+            ev_loop.needs_write(self)?;
+
+            // Nothing is written until the socket has room again, which is
+            // too late to tell producers about a crossing made by this send.
+            ev_loop.report_tx_progress(self);
+        }
+        Ok(len)
+    }
+
+    /// Writes as much of the transmit buffer as the socket accepts, leaving
+    /// the rest for the event loop. Unless the loop is writing right now,
+    /// in which case it picks all of it up.
+    fn write_through(&self, ev_loop: &EventLoop) -> io::Result<()> {
+        let remaining = match self.inner.flushing.try_lock() {
+            Some(_flushing) => {
+                if self.state() != State::Open { return Ok(()); }
+
+                let (sent, remaining) = self.send_all()?;
+                ev_loop.record_send(self, sent);
+                remaining
+            }
+            None => return ev_loop.needs_write(self)
+        };
+
+        // The handler is called without the flushing lock held, so that it
+        // can send from its callbacks.
+        ev_loop.report_tx_progress(self);
+        if remaining { ev_loop.needs_write(self)?; }
+        Ok(())
+    }
+
+    /// Writes the transmit buffer, then once it is empty, the pipe of
+    /// a connection spliced to this one. Must be called with the flushing
+    /// lock held.
+    fn send_all(&self) -> io::Result<(usize, bool)> {
+        let (sent, remaining) = self.write_socket()?;
+        if remaining { return Ok((sent, true)); }
+
+        let (source, pipe) = match self.inner.splicing.lock().from.clone() {
+            Some(from) => from,
+            None => return Ok((sent, false))
+        };
+
+        let (moved, remaining) = pipe.drain(self.socket)?;
+        // The source stopped reading once the pipe backed up.
+        if moved > 0 && !remaining {
+            let _ = source.interest_changed();
+        }
+        Ok((sent + moved, remaining))
+    }
+
+    /// Writes the transmit buffer to the transport. Must be called with
+    /// the flushing lock held.
+    fn write_socket(&self) -> io::Result<(usize, bool)> {
+        let mut transport = self.inner.transport.lock();
+        let mut zerocopy = self.inner.zerocopy.lock();
+        transport.send(&self.inner.tx_buf, zerocopy.as_mut())
+    }
+
+    /// Calls `f` with the application protocol selected with ALPN, without
+    /// copying it.
+    #[cfg(feature = "ssl")]
+    pub(crate) fn with_alpn_protocol<F, R>(&self, f: F) -> R
+        where F: FnOnce(Option<&[u8]>) -> R
+    {
+        let transport = self.inner.transport.lock();
+        f(transport.ssl().and_then(|ssl| ssl.selected_alpn_protocol()))
+    }
+
+    #[cfg(feature = "ssl")]
+    fn peer_x509(&self) -> Option<X509> {
+        self.inner.transport.lock().ssl().and_then(|ssl| ssl.peer_certificate())
+    }
+
+    fn splice_from(&self) -> Option<Arc<Pipe>> {
+        match self.inner.splicing.lock().from {
+            Some((_, ref pipe)) => Some(pipe.clone()),
+            None => None
+        }
+    }
+
+    /// Stops splicing to and from this connection, on both ends, so that
+    /// either connection left open can be spliced again. Whatever is left
+    /// in the pipe to the connection this one was spliced to is still sent.
+    fn unsplice(&self) {
+        let (to, from) = {
+            let splicing = self.inner.splicing.lock();
+            (splicing.to.as_ref().map(|(conn, _)| conn.clone()),
+             splicing.from.as_ref().map(|(conn, _)| conn.clone()))
+        };
+
+        if let Some(target) = to {
+            if let Some(pipe) = unsplice_pair(self, &target) {
+                match pipe.take_all() {
+                    Ok(ref rest) if !rest.is_empty() => {
+                        target.inner.tx_buf.append(rest);
+                        let _ = target.push();
+                    }
+                    Ok(_) => { }
+                    Err(e) => warn!("During unsplice {}", e)
+                }
+            }
+        }
+
+        // The source has nowhere left to send to, and goes back to reading
+        // into its receive buffer.
+        if let Some(source) = from {
+            unsplice_pair(&source, self);
+            let _ = source.interest_changed();
+        }
+    }
+
+    /// Removes bytes from the receive buffer with `f`, resuming reading from
+    /// the socket if that takes it back below its high watermark.
+    fn consume_rx<F, R>(&self, f: F) -> io::Result<R> where F: FnOnce(&Buffer) -> R {
+        self.consume_rx_scanned(|rx_buf, scanned| {
+            *scanned = None;
+            f(rx_buf)
+        })
+    }
+
+    /// Like `consume_rx`, also passing `f` what `read_until` knows of the
+    /// receive buffer, which `f` must forget if it removes any bytes.
+    fn consume_rx_scanned<F, R>(&self, f: F) -> io::Result<R>
+        where F: FnOnce(&Buffer, &mut Option<(u8, usize)>) -> R
+    {
+        let was_full = self.rx_full();
+        let r = {
+            let mut scanned = self.inner.rx_scanned.lock();
+            f(&self.inner.rx_buf, &mut scanned)
+        };
+        if was_full && !self.rx_full() {
+            self.interest_changed()?;
+        }
+        Ok(r)
+    }
+
+    fn rx_full(&self) -> bool {
+        match self.rx_high_watermark() {
+            Some(hw) => self.inner.rx_buf.len() >= hw,
+            None => false
+        }
+    }
+
+    fn interest_changed(&self) -> io::Result<()> {
+        let ev_loop = self.ev_loop()?;
+        ev_loop.interest_changed(self)
+    }
+
+    fn ev_loop(&self) -> io::Result<Arc<EventLoop>> {
+        match self.inner.ev_loop.upgrade() {
+            Some(ev_loop) => Ok(ev_loop),
+            None => Err(Error::new(ErrorKind::NotConnected, "Event loop stopped"))
+        }
+    }
+}
+
+/// Locks the splicing state of `a` and `b`, returned in that order, but
+/// always taken in the order the connections are laid out in memory so that
+/// threads working on the same pair cannot deadlock.
+fn lock_splicing<'a>(a: &'a Connection,
+                     b: &'a Connection) -> (MutexGuard<'a, Splicing>, MutexGuard<'a, Splicing>)
+{
+    if (&*a.inner as *const Inner) < (&*b.inner as *const Inner) {
+        let a_splicing = a.inner.splicing.lock();
+        (a_splicing, b.inner.splicing.lock())
+    } else {
+        let b_splicing = b.inner.splicing.lock();
+        (a.inner.splicing.lock(), b_splicing)
     }
 }
 
-unsafe impl Send for Connection { }
-unsafe impl Sync for Connection { }
+/// Stops `source` splicing to `target` on both ends, returning the pipe
+/// between them, or `None` if it already had.
+fn unsplice_pair(source: &Connection, target: &Connection) -> Option<Arc<Pipe>> {
+    let (mut source_splicing, mut target_splicing) = lock_splicing(source, target);
+    match source_splicing.to {
+        Some((ref conn, _)) if Arc::ptr_eq(&conn.inner, &target.inner) => { }
+        _ => return None
+    }
+
+    target_splicing.from = None;
+    source_splicing.to.take().map(|(_, pipe)| pipe)
+}
+
+fn no_codec() -> Error {
+    Error::new(ErrorKind::InvalidInput, "Connection has no codec")
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("socket", &self.socket)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Timeout::Idle => write!(f, "Idle timeout"),
+            Timeout::Read => write!(f, "Read timeout"),
+            Timeout::Write => write!(f, "Write timeout")
+        }
+    }
+}
+
+impl error::Error for Timeout {
+    fn description(&self) -> &str {
+        match *self {
+            Timeout::Idle => "Idle timeout",
+            Timeout::Read => "Read timeout",
+            Timeout::Write => "Write timeout"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Weak;
+
+    use super::{Config, Connection, UnixTransport};
+
+
+    /// Returns a connection with no event loop, along with its peer.
+    fn pair() -> (Connection, UnixStream) {
+        let (local, peer) = UnixStream::pair().unwrap();
+        local.set_nonblocking(true).unwrap();
+        let transport = Box::new(UnixTransport::new(local.into_raw_fd()));
+        let addr = "127.0.0.1:0".parse().unwrap();
+        (Connection::new(transport, addr, Config::default(), Weak::new()), peer)
+    }
+
+    /// Writes `bytes` from `peer` and reads them into the receive buffer.
+    fn receive(conn: &Connection, peer: &mut UnixStream, bytes: &[u8]) {
+        peer.write_all(bytes).unwrap();
+        assert_eq!(conn.read_socket(usize::MAX).unwrap(), bytes.len());
+    }
+
+    fn scanned(conn: &Connection) -> Option<(u8, usize)> {
+        *conn.inner.rx_scanned.lock()
+    }
+
+    #[test]
+    fn read_until_delimiter_split_across_reads() {
+        let (conn, mut peer) = pair();
+        receive(&conn, &mut peer, b"hel");
+        assert_eq!(conn.read_until(b'\n').unwrap(), None);
+        assert_eq!(scanned(&conn), Some((b'\n', 3)));
+
+        receive(&conn, &mut peer, b"lo\nrest");
+        assert_eq!(conn.read_until(b'\n').unwrap(), Some(b"hello\n".to_vec()));
+        assert_eq!(scanned(&conn), None);
+        assert_eq!(conn.bytes_avail().unwrap(), 4);
+    }
+
+    #[test]
+    fn read_until_other_delimiter_rescans() {
+        let (conn, mut peer) = pair();
+        receive(&conn, &mut peer, b"key,value");
+        assert_eq!(conn.read_until(b'\n').unwrap(), None);
+        assert_eq!(scanned(&conn), Some((b'\n', 9)));
+
+        assert_eq!(conn.read_until(b',').unwrap(), Some(b"key,".to_vec()));
+        assert_eq!(conn.read_until(b'\n').unwrap(), None);
+        assert_eq!(scanned(&conn), Some((b'\n', 5)));
+    }
+
+    #[test]
+    fn read_until_after_other_reads() {
+        let (conn, mut peer) = pair();
+        receive(&conn, &mut peer, b"abcd");
+        assert_eq!(conn.read_until(b'\n').unwrap(), None);
+
+        let mut buf = [0u8; 2];
+        assert_eq!(conn.recv(&mut buf).unwrap(), 2);
+        assert_eq!(scanned(&conn), None);
+        receive(&conn, &mut peer, b"\n");
+        assert_eq!(conn.read_until(b'\n').unwrap(), Some(b"cd\n".to_vec()));
+
+        receive(&conn, &mut peer, b"efg");
+        assert_eq!(conn.read_until(b'\n').unwrap(), None);
+        assert!(conn.read_exact(&mut buf).unwrap());
+        assert_eq!(scanned(&conn), None);
+        receive(&conn, &mut peer, b"\n");
+        assert_eq!(conn.read_until(b'\n').unwrap(), Some(b"g\n".to_vec()));
+    }
+}
@@ -1,10 +0,0 @@
-// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
-//
-// This Source Code Form is subject to the terms of the
-// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
-// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
-
-
-
-mod plain;
-mod ssl;
@@ -3,3 +3,48 @@
 // This Source Code Form is subject to the terms of the
 // Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use buf::Buffer;
+use socket;
+use zerocopy::ZeroCopy;
+
+use super::Transport;
+
+
+/// Bytes sent and received as is over a TCP socket.
+pub struct TcpTransport {
+    fd: RawFd
+}
+
+impl TcpTransport {
+    pub fn new(fd: RawFd) -> TcpTransport { TcpTransport { fd } }
+}
+
+impl Transport for TcpTransport {
+    fn fd(&self) -> RawFd { self.fd }
+
+    fn recv(&mut self, rx_buf: &Buffer, limit: usize) -> io::Result<usize> {
+        socket::recv(self.fd, rx_buf, limit)
+    }
+
+    fn send(&mut self,
+            tx_buf: &Buffer,
+            zerocopy: Option<&mut ZeroCopy>) -> io::Result<(usize, bool)>
+    {
+        socket::send(self.fd, tx_buf, zerocopy)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> { socket::shutdown_write(self.fd) }
+
+    fn shutdown(&mut self) -> io::Result<()> { socket::shutdown(self.fd) }
+
+    fn set_cork(&mut self, cork: bool) -> io::Result<()> {
+        socket::set_tcp_cork(self.fd, cork)
+    }
+
+    fn supports_zerocopy(&self) -> bool { true }
+}
@@ -3,3 +3,261 @@
 // This Source Code Form is subject to the terms of the
 // Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::cmp;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+
+use libc;
+use openssl::ssl::{self, ErrorCode, Ssl, SslContext, SslRef, SslStream};
+use openssl::x509::X509Ref;
+
+use buf::Buffer;
+use zerocopy::ZeroCopy;
+
+use super::Transport;
+
+
+/// Most plaintext read or written in a single call, the largest a TLS
+/// record can hold.
+const RECORD_LEN: usize = 16 * 1024;
+
+
+/// Server side of a TLS session, encrypting the bytes of the transport
+/// underneath it.
+pub struct TlsTransport {
+    stream: SslStream<Fd>,
+    /// Transport whose socket carries the session's records.
+    inner: Box<dyn Transport>,
+    handshaking: bool,
+    /// The last call could not go on until the socket became writable.
+    /// Only the handshake can wait on the other direction than its own,
+    /// renegotiation is refused, see `SslConfig`.
+    wants_write: bool
+}
+
+/// A name a certificate's subject alternative name extension holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr)
+}
+
+/// Nonblocking socket OpenSSL reads ciphertext from and writes it to.
+struct Fd(RawFd);
+
+impl TlsTransport {
+    /// Creates a new session accepting a handshake on the socket of
+    /// `inner`.
+    pub fn accept(ctx: &SslContext, inner: Box<dyn Transport>) -> io::Result<TlsTransport> {
+        let ssl = Ssl::new(ctx).map_err(Error::other)?;
+        let stream = SslStream::new(ssl, Fd(inner.fd())).map_err(Error::other)?;
+
+        Ok(TlsTransport {
+            stream,
+            inner,
+            handshaking: true,
+            wants_write: false
+        })
+    }
+
+    /// Records which way the socket has to become ready for a call that
+    /// could not complete, or returns the error that stopped it.
+    fn would_block(&mut self, err: ssl::Error) -> io::Result<()> {
+        match err.code() {
+            ErrorCode::WANT_READ => {
+                self.wants_write = false;
+                Ok(())
+            }
+            ErrorCode::WANT_WRITE => {
+                self.wants_write = true;
+                Ok(())
+            }
+            _ => Err(err.into_io_error().unwrap_or_else(Error::other))
+        }
+    }
+}
+
+impl Transport for TlsTransport {
+    fn fd(&self) -> RawFd { self.inner.fd() }
+
+    fn is_handshaking(&self) -> bool { self.handshaking }
+
+    fn handshake_wants_write(&self) -> bool { self.wants_write }
+
+    fn handshake(&mut self) -> io::Result<bool> {
+        if !self.handshaking { return Ok(true); }
+
+        match self.stream.accept() {
+            Ok(_) => {
+                self.handshaking = false;
+                self.wants_write = false;
+                Ok(true)
+            }
+            Err(err) => self.would_block(err).map(|_| false)
+        }
+    }
+
+    /// Records are always read in full, so nothing is left decrypted but
+    /// unread where epoll cannot see it.
+    fn recv(&mut self, rx_buf: &Buffer, limit: usize) -> io::Result<usize> {
+        let mut buf = [0u8; RECORD_LEN];
+        let mut total_recvd: usize = 0;
+        while total_recvd < limit || self.stream.ssl().pending() > 0 {
+            match self.stream.ssl_read(&mut buf) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "EOF")),
+                Ok(num_read) => {
+                    rx_buf.append(&buf[0..num_read]);
+                    total_recvd += num_read;
+                }
+                Err(err) => {
+                    if err.code() == ErrorCode::ZERO_RETURN {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "EOF"));
+                    }
+                    self.would_block(err)?;
+                    break;
+                }
+            }
+        }
+
+        self.wants_write = false;
+        Ok(total_recvd)
+    }
+
+    /// File regions are read into memory first, as their bytes have to pass
+    /// through OpenSSL. Sent counts plaintext bytes.
+    fn send(&mut self,
+            tx_buf: &Buffer,
+            _zerocopy: Option<&mut ZeroCopy>) -> io::Result<(usize, bool)>
+    {
+        let mut file_buf = [0u8; RECORD_LEN];
+        let mut total_sent: usize = 0;
+        self.wants_write = false;
+        loop {
+            let stream = &mut self.stream;
+            let file = tx_buf.with_front_file(|in_fd, offset, len| {
+                let len = cmp::min(len, RECORD_LEN);
+                let b = file_buf.as_mut_ptr() as *mut libc::c_void;
+                let r = unsafe { libc::pread(in_fd, b, len, offset as libc::off_t) };
+                if r == -1 { return Err(Error::last_os_error()); }
+                if r == 0 {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "File truncated"));
+                }
+                Ok(stream.ssl_write(&file_buf[..r as usize]))
+            });
+
+            let r = match file {
+                Some(r) => r?,
+                None => tx_buf.with_io_slices(1, |slices| match slices.first() {
+                    Some(slice) => stream.ssl_write(slice),
+                    None => Ok(0)
+                })
+            };
+
+            match r {
+                Ok(0) => return Ok((total_sent, false)),
+                Ok(sent) => {
+                    tx_buf.consume(sent);
+                    total_sent += sent;
+                }
+                Err(err) => {
+                    self.would_block(err)?;
+                    return Ok((total_sent, true));
+                }
+            }
+        }
+    }
+
+    /// Sends close_notify to the peer first, without waiting for theirs.
+    fn shutdown_write(&mut self) -> io::Result<()> {
+        let _ = self.stream.shutdown();
+        self.inner.shutdown_write()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        let _ = self.stream.shutdown();
+        self.inner.shutdown()
+    }
+
+    fn set_cork(&mut self, cork: bool) -> io::Result<()> { self.inner.set_cork(cork) }
+
+    fn is_tls(&self) -> bool { true }
+
+    fn ssl(&self) -> Option<&SslRef> { Some(self.stream.ssl()) }
+}
+
+/// Returns the subject of `cert` as comma separated `name=value` pairs,
+/// in the order they appear in the certificate, such as
+/// `C=US, O=Example, CN=client`.
+pub fn subject(cert: &X509Ref) -> String {
+    let mut subject = String::new();
+    for entry in cert.subject_name().entries() {
+        let name = entry.object().nid().short_name().unwrap_or("UNDEF");
+        let value = match entry.data().to_string() {
+            Ok(value) => value,
+            Err(_) => String::from_utf8_lossy(entry.data().as_slice()).into_owned()
+        };
+
+        if !subject.is_empty() { subject.push_str(", "); }
+        subject.push_str(name);
+        subject.push('=');
+        subject.push_str(&value);
+    }
+    subject
+}
+
+/// Returns every name of a supported kind in the subject alternative name
+/// extension of `cert`.
+pub fn subject_alt_names(cert: &X509Ref) -> Vec<SubjectAltName> {
+    let names = match cert.subject_alt_names() {
+        Some(names) => names,
+        None => return Vec::new()
+    };
+
+    names.iter().filter_map(|name| {
+        if let Some(dns) = name.dnsname() {
+            return Some(SubjectAltName::Dns(dns.to_owned()));
+        }
+        if let Some(email) = name.email() {
+            return Some(SubjectAltName::Email(email.to_owned()));
+        }
+        if let Some(uri) = name.uri() {
+            return Some(SubjectAltName::Uri(uri.to_owned()));
+        }
+        name.ipaddress().and_then(ip_addr).map(SubjectAltName::Ip)
+    }).collect()
+}
+
+fn ip_addr(b: &[u8]) -> Option<IpAddr> {
+    if b.len() == 4 {
+        return Some(IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3])));
+    }
+    if b.len() == 16 {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(b);
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+    None
+}
+
+impl Read for Fd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let b = buf.as_mut_ptr() as *mut libc::c_void;
+        let r = unsafe { libc::recv(self.0, b, buf.len(), 0) };
+        if r == -1 { Err(Error::last_os_error()) } else { Ok(r as usize) }
+    }
+}
+
+impl Write for Fd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let b = buf.as_ptr() as *const libc::c_void;
+        let r = unsafe { libc::send(self.0, b, buf.len(), libc::MSG_NOSIGNAL) };
+        if r == -1 { Err(Error::last_os_error()) } else { Ok(r as usize) }
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
@@ -0,0 +1,47 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use buf::Buffer;
+use socket;
+use zerocopy::ZeroCopy;
+
+use super::Transport;
+
+
+/// Bytes sent and received as is over a Unix-domain stream socket.
+///
+/// The kernel copies straight from one socket to the other, so there is no
+/// `TCP_CORK` to set and nothing to gain from `MSG_ZEROCOPY`.
+pub struct UnixTransport {
+    fd: RawFd
+}
+
+impl UnixTransport {
+    pub fn new(fd: RawFd) -> UnixTransport { UnixTransport { fd } }
+}
+
+impl Transport for UnixTransport {
+    fn fd(&self) -> RawFd { self.fd }
+
+    fn recv(&mut self, rx_buf: &Buffer, limit: usize) -> io::Result<usize> {
+        socket::recv(self.fd, rx_buf, limit)
+    }
+
+    fn send(&mut self,
+            tx_buf: &Buffer,
+            _zerocopy: Option<&mut ZeroCopy>) -> io::Result<(usize, bool)>
+    {
+        socket::send(self.fd, tx_buf, None)
+    }
+
+    fn shutdown_write(&mut self) -> io::Result<()> { socket::shutdown_write(self.fd) }
+
+    fn shutdown(&mut self) -> io::Result<()> { socket::shutdown(self.fd) }
+}
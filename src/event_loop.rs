@@ -6,10 +6,13 @@
 
 
 use std::collections::BTreeMap;
+use std::fs::File;
 use std::io::{self, Error, ErrorKind};
 use std::mem;
 use std::os::unix::io::RawFd;
-use std::thread;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use epoll::{
     self,
@@ -25,177 +28,1139 @@ use epoll::{
     EPOLLHUP,
     EPOLLRDHUP
 };
-use parking_lot::Mutex;
+use parking_lot::{Condvar, Mutex};
 
-use conn::Connection;
+use codec::Codec;
+use conn::{Config, Connection, Expiry, State};
+use handler::Handler;
+use listener::Listener;
+use pipe::Pipe;
 use socket;
+use timer::{self, TimerId, Wheel};
 
 
 type ConnectionMap = Mutex<BTreeMap<RawFd, Connection>>;
 
 
-static mut EPFD: RawFd = 0;
-
-lazy_static! {
-    static ref CONN_MAP: ConnectionMap = Mutex::new(BTreeMap::new());
-}
+/// Resolution of timers.
+const TIMER_TICK_MS: u64 = 10;
 
+/// Number of slots in the timer wheel, one revolution covers
+/// `TIMER_TICK_MS * TIMER_SLOTS` milliseconds.
+const TIMER_SLOTS: usize = 512;
 
-pub fn init() -> io::Result<()> {
-    unsafe { EPFD = try!(epoll::create(true)); }
 
-    info!("epfd: {}", epfd());
+/// An epoll instance along with the connections registered with it.
+pub struct EventLoop {
+    id: usize,
+    epfd: RawFd,
+    /// eventfd used to wake the event loop when it needs to exit, or has
+    /// been handed new connections.
+    wake_fd: RawFd,
+    /// timerfd armed for the nearest timer in `timers`.
+    timer_fd: RawFd,
+    running: AtomicBool,
+    conns: ConnectionMap,
+    /// Connections handed to the loop from other threads, waiting to be
+    /// registered by its own.
+    pending: Mutex<Vec<Connection>>,
+    /// Number of connections handed to the loop that it has not yet
+    /// registered, whether still in `pending` or being registered.
+    handoffs: AtomicUsize,
+    /// Set by `drain`, connections announced from then on are drained as
+    /// soon as the handler has been told about them.
+    drain_timeout: Mutex<Option<Duration>>,
+    /// Signalled every time the last connection is removed.
+    conns_empty: Condvar,
+    timers: Mutex<Timers>,
+    /// Settings applied to every new connection.
+    config: Config,
+    listener: Mutex<Option<Arc<Listener>>>,
+    handler: Arc<dyn Handler>,
+    /// Codec given to every new connection.
+    codec: Option<Arc<dyn Codec>>,
+    counters: Counters
+}
 
-    thread::spawn(event_loop);
+/// Snapshot of an event loop's activity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Index of the event loop within its server's pool.
+    pub id: usize,
+    /// Number of connections currently registered with the loop.
+    pub connections: usize,
+    /// Total number of connections ever handed to the loop.
+    pub accepted: usize,
+    /// Total number of epoll events processed.
+    pub events: usize,
+    /// Total number of bytes received.
+    pub bytes_recv: usize,
+    /// Total number of bytes sent.
+    pub bytes_sent: usize
+}
 
-    Ok(())
+/// Cloneable handle to an event loop, used to schedule timers whose
+/// callbacks run on the loop's thread.
+///
+/// Handles do not keep the loop alive, once its server has been shutdown
+/// scheduling fails with `NotConnected`.
+#[derive(Clone)]
+pub struct LoopHandle {
+    ev_loop: Weak<EventLoop>
 }
 
-pub fn add_conn(conn: Connection) -> io::Result<()> {
-    map_add(conn);
-    let e = epoll::Event::new(epoll_events_r(), conn.socket as u64);
-    epoll_add(e)
+/// Pending timers, and the instant the timerfd is armed for.
+struct Timers {
+    wheel: Wheel<Timer>,
+    armed: Option<Instant>,
+    /// Callback timers taken from the wheel with the batch being run that
+    /// have not been started yet.
+    expired: Vec<TimerId>,
+    /// Interval currently being run by the loop, and whether it was
+    /// cancelled while running.
+    running: Option<(TimerId, bool)>
 }
 
-pub fn del_conn(conn: Connection) -> io::Result<()> {
-    map_del(conn);
-    let e = epoll::Event::new(epoll_events_r(), conn.socket as u64);
-    epoll_del(e)
+enum Timer {
+    /// Checks the connection's deadlines.
+    Conn(Connection),
+    /// Runs the callback, and again every `interval` if set.
+    Callback {
+        f: Box<dyn FnMut() + Send>,
+        interval: Option<Duration>
+    }
 }
 
-pub fn needs_write(fd: RawFd) -> io::Result<()> {
-    let e = epoll::Event::new(epoll_events_rw(), fd as u64);
-    epoll_mod(e)
+#[derive(Default)]
+struct Counters {
+    accepted: AtomicUsize,
+    events: AtomicUsize,
+    bytes_recv: AtomicUsize,
+    bytes_sent: AtomicUsize
 }
 
-fn event_loop() {
-    info!("Starting event loop");
+impl EventLoop {
+    /// Creates a new epoll instance whose connection events are dispatched
+    /// to `handler`, framed by `codec` if set. `id` identifies the loop
+    /// within its server's pool.
+    pub fn new(id: usize,
+               handler: Arc<dyn Handler>,
+               codec: Option<Arc<dyn Codec>>,
+               config: Config) -> io::Result<EventLoop>
+    {
+        let epfd = epoll::create(true)?;
+        let wake_fd = match socket::eventfd() {
+            Ok(fd) => fd,
+            Err(err) => {
+                let _ = socket::close(epfd);
+                return Err(err);
+            }
+        };
+        let timer_fd = match timer::timerfd() {
+            Ok(fd) => fd,
+            Err(err) => {
+                let _ = socket::close(wake_fd);
+                let _ = socket::close(epfd);
+                return Err(err);
+            }
+        };
 
-    const WAIT_FOREVER: i32 = -1;
-    let mut buf: [epoll::Event; 100] = unsafe { mem::uninitialized() };
-    loop {
-        let r = epoll::wait(epfd(), WAIT_FOREVER, &mut buf);
-        if r.is_err() {
-            let err = r.unwrap_err();
-            error!("{} during epoll::wait", err);
-            return;
+        let ev_loop = EventLoop {
+            id,
+            epfd,
+            wake_fd,
+            timer_fd,
+            running: AtomicBool::new(true),
+            conns: Mutex::new(BTreeMap::new()),
+            pending: Mutex::new(Vec::new()),
+            handoffs: AtomicUsize::new(0),
+            drain_timeout: Mutex::new(None),
+            conns_empty: Condvar::new(),
+            timers: Mutex::new(Timers {
+                wheel: Wheel::new(Duration::from_millis(TIMER_TICK_MS), TIMER_SLOTS),
+                armed: None,
+                expired: Vec::new(),
+                running: None
+            }),
+            config,
+            listener: Mutex::new(None),
+            handler,
+            codec,
+            counters: Counters::default()
+        };
+
+        info!("Event loop {} epfd: {}", id, epfd);
+
+        // The wake and timer fds are level triggered, they are reset by
+        // the loop every time they fire.
+        let e = epoll::Event::new(EPOLLIN, wake_fd as u64);
+        ev_loop.epoll_add(e)?;
+        let e = epoll::Event::new(EPOLLIN, timer_fd as u64);
+        ev_loop.epoll_add(e)?;
+
+        Ok(ev_loop)
+    }
+
+    /// Registers `listener` with this loop, connections are then accepted
+    /// from within the loop whenever it becomes readable.
+    pub fn listen(&self, listener: Listener) -> io::Result<()> {
+        let e = epoll::Event::new(EPOLLET | EPOLLIN, listener.fd() as u64);
+        self.epoll_add(e)?;
+        *self.listener.lock() = Some(Arc::new(listener));
+        Ok(())
+    }
+
+    /// Waits for and processes epoll events until `shutdown` is called.
+    pub fn run(&self) {
+        info!("Starting event loop {}", self.id);
+
+        const WAIT_FOREVER: i32 = -1;
+        let mut buf = [epoll::Event::new(epoll::Events::empty(), 0); 100];
+        loop {
+            let num_events = match epoll::wait(self.epfd, WAIT_FOREVER, &mut buf) {
+                Ok(num_events) => num_events,
+                Err(err) => {
+                    error!("{} during epoll::wait", err);
+                    return;
+                }
+            };
+            trace!("{} events to process", num_events);
+            self.counters.events.fetch_add(num_events, Ordering::Relaxed);
+
+            for x in 0..num_events {
+                let e = unsafe { buf.get_unchecked(x) };
+                let fd = e.data() as RawFd;
+                if fd == self.wake_fd {
+                    socket::eventfd_reset(self.wake_fd);
+                    if !self.running.load(Ordering::SeqCst) {
+                        info!("Event loop woken for shutdown");
+                        return self.teardown();
+                    }
+                    self.adopt_pending();
+                    self.drain_conns();
+                    continue;
+                }
+
+                if fd == self.timer_fd {
+                    self.handle_timer_event();
+                    continue;
+                }
+
+                match self.listener_for(fd) {
+                    Some(l) => handle_listener_event(&l, e),
+                    None => self.handle_epoll_event(e)
+                }
+            }
         }
+    }
 
-        let num_events = r.unwrap();
-        trace!("{} events to process", num_events);
+    /// Wakes the event loop and signals it to exit. All connections still
+    /// registered are shutdown by the loop thread before it returns.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.running.store(false, Ordering::SeqCst);
+        self.wake()
+    }
 
-        for x in 0..num_events {
-            let e = unsafe { buf.get_unchecked(x) };
-            handle_epoll_event(e);
+    /// Deregisters and closes this loop's listener, if it has one.
+    pub fn stop_listening(&self) {
+        if let Some(listener) = self.listener.lock().take() {
+            let e = epoll::Event::new(EPOLLIN, listener.fd() as u64);
+            let _ = self.epoll_del(e);
         }
     }
-}
 
-fn handle_epoll_event(e: &epoll::Event) {
-    if close_event(e.events()) {
-        handle_close_event(e);
-    } else {
-        if read_event(e.events()) {
-            handle_read_event(e);
+    /// Gracefully closes every connection of this loop, see
+    /// `Connection::close_after_flush`, including any handed to it later.
+    ///
+    /// The loop thread does the closing, so that no connection is closed
+    /// between being registered and being announced to the handler.
+    pub fn drain(&self, timeout: Duration) -> io::Result<()> {
+        *self.drain_timeout.lock() = Some(timeout);
+        self.wake()
+    }
+
+    /// Blocks until every connection has been removed from this loop, and
+    /// none are waiting to be adopted, or `deadline` has passed, returning
+    /// true in the former case.
+    pub fn wait_drained(&self, deadline: Instant) -> bool {
+        let mut map = self.conns.lock();
+        while !map.is_empty() || self.handoffs.load(Ordering::SeqCst) > 0 {
+            if self.conns_empty.wait_until(&mut map, deadline).timed_out() {
+                return map.is_empty() && self.handoffs.load(Ordering::SeqCst) == 0;
+            }
         }
+        true
+    }
 
-        if write_event(e.events()) {
-            handle_write_event(e);
+    /// Starts draining `conn`, it is forcibly closed if still open at its
+    /// drain deadline.
+    pub fn drain_conn(&self, conn: &Connection) -> io::Result<()> {
+        self.schedule_timer(conn);
+        self.rearm(conn)
+    }
+
+    /// Makes sure `conn`'s timer fires no later than its nearest deadline.
+    pub fn schedule_timer(&self, conn: &Connection) {
+        let mut timers = self.timers.lock();
+        let next = conn.next_expiry().map(|(at, _)| at);
+
+        match (conn.timer(), next) {
+            // Already scheduled to fire early enough, if it fires too
+            // early it is rescheduled then.
+            (Some((_, at)), Some(next)) if at <= next => return,
+            (Some((id, _)), _) => {
+                timers.wheel.remove(id);
+                conn.set_timer(None);
+            }
+            (None, _) => { }
+        }
+
+        if let Some(next) = next {
+            let id = timers.wheel.insert(next, Timer::Conn(conn.clone()));
+            conn.set_timer(Some((id, next)));
+            self.arm_timer(&mut timers, next);
         }
     }
-}
 
-fn handle_close_event(e: &epoll::Event) {
-    let fd = e.data() as RawFd;
+    /// Runs `f` on this loop's thread once `after` has elapsed.
+    pub fn set_timeout<F>(&self, after: Duration, f: F) -> TimerId
+        where F: FnOnce() + Send + 'static
+    {
+        let mut f = Some(f);
+        let f = move || if let Some(f) = f.take() { f() };
+        self.add_timer(after, Box::new(f), None)
+    }
+
+    /// Runs `f` on this loop's thread every `every` until cancelled.
+    pub fn set_interval<F>(&self, every: Duration, f: F) -> TimerId
+        where F: FnMut() + Send + 'static
+    {
+        self.add_timer(every, Box::new(f), Some(every))
+    }
+
+    /// Cancels a timer scheduled with `set_timeout` or `set_interval`,
+    /// returning false if it had already fired or been cancelled.
+    ///
+    /// A timeout has fired as soon as its callback starts running. An
+    /// interval can be cancelled while its callback runs, even from within
+    /// it, and is then not run again.
+    pub fn cancel_timer(&self, id: TimerId) -> bool {
+        let mut timers = self.timers.lock();
+        if timers.wheel.remove(id).is_some() { return true; }
+
+        // An earlier callback of the same batch may cancel a timer that
+        // has already left the wheel.
+        if let Some(pos) = timers.expired.iter().position(|&e| e == id) {
+            timers.expired.remove(pos);
+            return true;
+        }
+
+        // An interval being run right now is not in the wheel, it must not
+        // be put back once it returns. A timeout being run has fired.
+        match timers.running {
+            Some((running_id, false)) if running_id == id => {
+                timers.running = Some((id, true));
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// Hands `conn` to this loop, its own thread registers it and tells the
+    /// handler about it before handling any of its events.
+    pub fn adopt(&self, conn: Connection) -> io::Result<()> {
+        self.handoffs.fetch_add(1, Ordering::SeqCst);
+        self.pending.lock().push(conn);
+        self.wake()
+    }
+
+    pub fn add_conn(&self, conn: Connection) -> io::Result<()> {
+        conn.set_codec(self.codec.clone());
+
+        let e = epoll::Event::new(epoll_events_r(), conn.socket as u64);
+        self.map_add(conn.clone());
+        if let Err(err) = self.epoll_add(e) {
+            self.map_del(&conn);
+            return Err(err);
+        }
 
-    let err = {
-        if socket_error(e.events()) {
-            match socket::get_last_error(fd) {
-                Some(err) => err,
-                None => Error::new(ErrorKind::Other, "Unknown SocketError")
+        // Configured server wide, so left off for connections whose
+        // transport cannot send zero-copy.
+        match self.config.zerocopy {
+            Some(min_len) if conn.supports_zerocopy() => {
+                let _ = conn.set_zerocopy(Some(min_len)).map_err(|err| {
+                    warn!("{} enabling zero-copy for {:?}", err, conn);
+                });
             }
+            _ => { }
+        }
+
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        self.schedule_timer(&conn);
+        Ok(())
+    }
+
+    pub fn del_conn(&self, conn: &Connection) -> io::Result<()> {
+        let e = epoll::Event::new(epoll_events_r(), conn.socket as u64);
+        let r = self.epoll_del(e);
+        self.map_del(conn);
+        r
+    }
+
+    pub fn needs_write(&self, conn: &Connection) -> io::Result<()> {
+        // Pending data may bring a write timeout into play.
+        self.schedule_timer(conn);
+        self.rearm(conn)
+    }
+
+    /// Re-arms `conn` after something that decides which events it is
+    /// interested in has changed.
+    pub fn interest_changed(&self, conn: &Connection) -> io::Result<()> {
+        match conn.state() {
+            State::Closed => Ok(()),
+            _ => self.rearm(conn)
+        }
+    }
+
+    /// Returns the settings applied to new connections.
+    pub fn config(&self) -> Config { self.config }
+
+    /// Returns the number of connections currently registered.
+    pub fn num_conns(&self) -> usize { self.conns.lock().len() }
+
+    /// Returns the number of connections registered with this loop, or
+    /// handed to it and about to be.
+    pub fn load(&self) -> usize {
+        self.num_conns() + self.handoffs.load(Ordering::SeqCst)
+    }
+
+    /// Returns a snapshot of this loop's activity.
+    pub fn stats(&self) -> LoopStats {
+        LoopStats {
+            id: self.id,
+            connections: self.num_conns(),
+            accepted: self.counters.accepted.load(Ordering::Relaxed),
+            events: self.counters.events.load(Ordering::Relaxed),
+            bytes_recv: self.counters.bytes_recv.load(Ordering::Relaxed),
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn on_connect(&self, conn: &Connection) {
+        info!("New connection: {:?}", conn);
+        self.handler.on_connect(conn);
+
+        // Connected after the loop started draining, including ones still
+        // handshaking when it did.
+        if let Some(timeout) = *self.drain_timeout.lock() {
+            let _ = conn.close_after_flush(timeout);
+        }
+    }
+
+    pub fn on_recv(&self, conn: &Connection) {
+        self.handler.on_recv(conn);
+    }
+
+    pub fn on_message(&self, conn: &Connection, frame: Vec<u8>) {
+        self.handler.on_message(conn, frame);
+    }
+
+    pub fn on_error(&self, conn: &Connection, err: io::Error) {
+        debug!("Connection {:?} error: {}", conn, err);
+        self.handler.on_error(conn, err);
+    }
+
+    pub fn on_tx_high_watermark(&self, conn: &Connection) {
+        debug!("Connection {:?} transmit buffer past high watermark", conn);
+        self.handler.on_tx_high_watermark(conn);
+    }
+
+    pub fn on_tx_drained(&self, conn: &Connection) {
+        self.handler.on_tx_drained(conn);
+    }
+
+    pub fn on_file_sent(&self, conn: &Connection, file: File) {
+        debug!("Connection {:?} finished sending file", conn);
+        self.handler.on_file_sent(conn, file);
+    }
+
+    /// Hands every file `conn` has finished sending back to the handler.
+    pub fn report_sent_files(&self, conn: &Connection) {
+        for file in conn.tx_buf().take_sent_files() {
+            self.on_file_sent(conn, file);
+        }
+    }
+
+    /// Tells the handler about everything a write to `conn` has changed:
+    /// files finished sending, and the transmit buffer crossing either of
+    /// its watermarks.
+    pub fn report_tx_progress(&self, conn: &Connection) {
+        self.report_sent_files(conn);
+
+        let (high, drained) = conn.tx_watermarks();
+        if high { self.on_tx_high_watermark(conn); }
+        if drained { self.on_tx_drained(conn); }
+    }
+
+    pub fn on_close(&self, conn: &Connection) {
+        debug!("Connection {:?} closed", conn);
+        self.handler.on_close(conn);
+    }
+
+    /// Registers every connection handed to this loop since it last woke.
+    ///
+    /// Runs on the loop's thread, so none of a connection's events can be
+    /// handled until `on_connect` has returned. Connections still in their
+    /// handshake are announced once it completes instead.
+    fn adopt_pending(&self) {
+        let pending = mem::take(&mut *self.pending.lock());
+        for conn in pending {
+            let r = self.add_conn(conn.clone());
+            self.handoffs.fetch_sub(1, Ordering::SeqCst);
+            match r {
+                Ok(_) if conn.is_handshaking() => { }
+                Ok(_) => self.on_connect(&conn),
+                Err(e) => {
+                    warn!("During epoll add {}", e);
+                    conn.abandon();
+
+                    // Never registered, so never removed either, wake
+                    // wait_drained in case this was the last one.
+                    let map = self.conns.lock();
+                    if map.is_empty() { self.conns_empty.notify_all(); }
+                }
+            }
+        }
+    }
+
+    /// Drains every registered connection once `drain` has been called.
+    fn drain_conns(&self) {
+        let timeout = match *self.drain_timeout.lock() {
+            Some(timeout) => timeout,
+            None => return
+        };
+
+        let conns: Vec<Connection> = {
+            let map = self.conns.lock();
+            map.values().cloned().collect()
+        };
+
+        for conn in conns { let _ = conn.close_after_flush(timeout); }
+    }
+
+    fn teardown(&self) {
+        // Dropping the listener closes it, refusing any further connections.
+        self.listener.lock().take();
+
+        // Connections accepted right before the shutdown are announced, so
+        // that the handler sees every connection it is told is closed.
+        self.adopt_pending();
+
+        let conns: Vec<Connection> = {
+            let map = self.conns.lock();
+            map.values().cloned().collect()
+        };
+
+        for conn in conns { let _ = conn.shutdown(); }
+
+        info!("Event loop {} stopped", self.id);
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        socket::eventfd_notify(self.wake_fd)
+    }
+
+    fn cancel_conn_timer(&self, conn: &Connection) {
+        let mut timers = self.timers.lock();
+        if let Some((id, _)) = conn.timer() {
+            timers.wheel.remove(id);
+            conn.set_timer(None);
+        }
+    }
+
+    fn listener_for(&self, fd: RawFd) -> Option<Arc<Listener>> {
+        match *self.listener.lock() {
+            Some(ref l) if l.fd() == fd => Some(l.clone()),
+            _ => None
+        }
+    }
+
+    fn handle_timer_event(&self) {
+        timer::timerfd_reset(self.timer_fd);
+
+        let now = Instant::now();
+        let expired = {
+            let mut timers = self.timers.lock();
+            let expired = timers.wheel.expire(now);
+            timers.expired = expired.iter()
+                .filter_map(|&(id, ref t)| match *t {
+                    Timer::Callback { .. } => Some(id),
+                    Timer::Conn(_) => None
+                })
+                .collect();
+
+            timers.armed = timers.wheel.next_expiry();
+            let _ = timer::timerfd_arm(self.timer_fd, timers.armed).map_err(|e| {
+                error!("During timerfd arm {}", e);
+            });
+
+            expired
+        };
+
+        // Timers are run without holding the lock, so callbacks are free
+        // to schedule and cancel timers themselves.
+        for (id, t) in expired {
+            match t {
+                Timer::Conn(conn) => self.on_conn_timer(id, conn, now),
+                Timer::Callback { f, interval } => {
+                    if self.take_expired(id) {
+                        self.on_callback_timer(id, f, interval);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_conn_timer(&self, id: TimerId, conn: Connection, now: Instant) {
+        {
+            // Connections only ever have a single timer, anything else
+            // was replaced by a later call to schedule_timer.
+            let _timers = self.timers.lock();
+            match conn.timer() {
+                Some((timer_id, _)) if timer_id == id => conn.set_timer(None),
+                _ => return
+            }
+        }
+
+        if conn.state() == State::Closed { return; }
+
+        match conn.next_expiry() {
+            Some((at, expiry)) if at <= now => self.on_expiry(&conn, expiry),
+            _ => self.schedule_timer(&conn)
+        }
+    }
+
+    /// Removes `id` from the batch being run, returning false if it was
+    /// cancelled since the batch left the wheel.
+    fn take_expired(&self, id: TimerId) -> bool {
+        let mut timers = self.timers.lock();
+        match timers.expired.iter().position(|&e| e == id) {
+            Some(pos) => {
+                timers.expired.remove(pos);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn on_callback_timer(&self,
+                         id: TimerId,
+                         mut f: Box<dyn FnMut() + Send>,
+                         interval: Option<Duration>)
+    {
+        if interval.is_some() {
+            self.timers.lock().running = Some((id, false));
+        }
+        f();
+
+        let mut timers = self.timers.lock();
+        let cancelled = match timers.running.take() {
+            Some((_, cancelled)) => cancelled,
+            None => false
+        };
+
+        if let (Some(every), false) = (interval, cancelled) {
+            let at = Instant::now() + every;
+            timers.wheel.reinsert(id, at, Timer::Callback { f, interval });
+            self.arm_timer(&mut timers, at);
+        }
+    }
+
+    fn add_timer(&self,
+                 after: Duration,
+                 f: Box<dyn FnMut() + Send>,
+                 interval: Option<Duration>) -> TimerId
+    {
+        let at = Instant::now() + after;
+        let mut timers = self.timers.lock();
+        let id = timers.wheel.insert(at, Timer::Callback { f, interval });
+        self.arm_timer(&mut timers, at);
+        id
+    }
+
+    /// Arms the timerfd for `at` if that is sooner than it is armed for.
+    fn arm_timer(&self, timers: &mut Timers, at: Instant) {
+        let arm = match timers.armed {
+            Some(armed) => at < armed,
+            None => true
+        };
+
+        if arm {
+            timers.armed = Some(at);
+            let _ = timer::timerfd_arm(self.timer_fd, Some(at)).map_err(|e| {
+                error!("During timerfd arm {}", e);
+            });
+        }
+    }
+
+    fn on_expiry(&self, conn: &Connection, expiry: Expiry) {
+        match expiry {
+            Expiry::Drain => debug!("Drain deadline passed for {:?}", conn),
+            Expiry::Timeout(timeout) => {
+                let err = Error::new(ErrorKind::TimedOut, timeout);
+                self.on_error(conn, err);
+            }
+        }
+
+        let _ = conn.shutdown();
+    }
+
+    fn handle_epoll_event(&self, e: &epoll::Event) {
+        let fd = e.data() as RawFd;
+        let conn = match self.map_get(fd) {
+            Some(conn) => conn,
+            None => return warn!("Unable to retrieve socket from map")
+        };
+
+        let e = &match self.handle_zerocopy_event(&conn, e) {
+            Some(e) => e,
+            None => return
+        };
+
+        let state = conn.state();
+
+        match state {
+            State::Open => { }
+            State::Closed => return,
+            _ => return self.handle_drain_event(e)
+        }
+
+        if conn.is_handshaking() {
+            return self.handle_handshake_event(&conn, e);
+        }
+
+        if close_event(e.events()) {
+            self.handle_close_event(e);
         } else {
-            Error::new(ErrorKind::UnexpectedEof, "EOF")
+            if read_event(e.events()) {
+                self.handle_read_event(e);
+            }
+
+            if write_event(e.events()) {
+                self.handle_write_event(e);
+            }
         }
-    };
+    }
 
-    match map_get(fd) {
-        Some(conn) => super::on_error(conn, err),
-        None => warn!("epoll reported close event, but socket not in map")
-    };
-}
+    /// Reaps zero-copy completions, which the kernel reports with EPOLLERR.
+    /// EPOLLERR then only means the socket failed if it also has an error
+    /// pending, otherwise returns the event without it for whatever else
+    /// it may report.
+    fn handle_zerocopy_event(&self,
+                             conn: &Connection,
+                             e: &epoll::Event) -> Option<epoll::Event>
+    {
+        if !socket_error(e.events()) || !conn.is_zerocopy() {
+            return Some(epoll::Event::new(e.events(), e.data()));
+        }
+
+        let r = conn.reap_zerocopy();
+        let err = match (r, socket::get_last_error(conn.socket)) {
+            (_, Some(err)) => err,
+            (Err(err), None) => err,
+            (Ok(_), None) => {
+                let mut events = e.events();
+                events.remove(EPOLLERR);
+                if (events & (EPOLLIN | EPOLLOUT | EPOLLHUP | EPOLLRDHUP)).bits() == 0 {
+                    self.epoll_rearm(conn);
+                    return None;
+                }
+                return Some(epoll::Event::new(events, e.data()));
+            }
+        };
 
-fn handle_read_event(e: &epoll::Event) {
-    let fd = e.data() as RawFd;
-    match map_get(fd) {
-        Some(conn) => match socket::recv(fd) {
+        match conn.state() {
+            State::Open => self.on_error(conn, err),
+            _ => { let _ = conn.shutdown(); }
+        }
+        None
+    }
+
+    /// Drives a TLS handshake, the handler is told about the connection
+    /// once it has completed. A failed handshake is reported to `on_error`
+    /// and the connection is shutdown.
+    fn handle_handshake_event(&self, conn: &Connection, e: &epoll::Event) {
+        if close_event(e.events()) {
+            debug!("Connection {:?} closed during TLS handshake", conn);
+            let _ = conn.shutdown();
+            return;
+        }
+
+        match conn.handshake() {
+            Ok(true) => {
+                debug!("TLS handshake with {:?} complete", conn);
+                self.on_connect(conn);
+
+                // Rearming reports whatever the peer sent right behind the
+                // end of the handshake.
+                self.epoll_rearm(conn);
+            }
+            Ok(false) => self.epoll_rearm(conn),
+            Err(err) => {
+                self.on_error(conn, err);
+                let _ = conn.shutdown();
+            }
+        }
+    }
+
+    fn handle_close_event(&self, e: &epoll::Event) {
+        let fd = e.data() as RawFd;
+
+        let err = {
+            if socket_error(e.events()) {
+                match socket::get_last_error(fd) {
+                    Some(err) => err,
+                    None => Error::other("Unknown SocketError")
+                }
+            } else {
+                Error::new(ErrorKind::UnexpectedEof, "EOF")
+            }
+        };
+
+        match self.map_get(fd) {
+            Some(conn) => self.on_error(&conn, err),
+            None => warn!("epoll reported close event, but socket not in map")
+        };
+    }
+
+    fn handle_read_event(&self, e: &epoll::Event) {
+        let fd = e.data() as RawFd;
+        let conn = match self.map_get(fd) {
+            Some(conn) => conn,
+            None => return warn!("Unable to retrieve socket from map")
+        };
+
+        // Reading was paused, or the receive buffer filled up, after the
+        // event was armed. Whatever is pending stays in the socket.
+        if !conn.wants_read() {
+            return self.epoll_rearm(&conn);
+        }
+
+        if let Some((target, pipe)) = conn.splice_to_target() {
+            return self.handle_splice_read(&conn, &target, &pipe);
+        }
+
+        match conn.read_socket(conn.rx_room()) {
             Ok(read) => {
                 debug!("Recv {} bytes from {:?}", read, conn);
-                epoll_rearm_r(fd);
-                super::on_recv(conn);
+                self.record_recv(&conn, read);
+                self.epoll_rearm(&conn);
+                self.dispatch_recv(&conn);
             }
-            Err(err) => super::on_error(conn, err)
-        },
-        None => warn!("Unable to retrieve socket from map")
+            Err(err) => self.on_error(&conn, err)
+        }
     }
-}
 
-fn handle_write_event(e: &epoll::Event) {
-    let fd = e.data() as RawFd;
-    match map_get(fd) {
-        Some(conn) => match socket::send(fd) {
-            Ok((sent, rearm_rw)) => {
-                debug!("Sent {} bytes to {:?}", sent, conn);
-                if rearm_rw {
-                    epoll_rearm_rw(fd);
-                } else {
-                    epoll_rearm_r(fd);
+    /// Moves everything `conn` has received to the connection it is spliced
+    /// to, until either `conn` has nothing left or the target cannot take
+    /// any more, see `Connection::splice_to`.
+    fn handle_splice_read(&self, conn: &Connection, target: &Connection, pipe: &Pipe) {
+        let mut read = 0;
+        let r = loop {
+            let filled = match pipe.fill(conn.socket) {
+                Ok(0) => break Err(Error::new(ErrorKind::UnexpectedEof, "EOF")),
+                Ok(filled) => filled,
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock { break Err(err); }
+                    0
+                }
+            };
+            read += filled;
+
+            // The target writes the pipe out behind its transmit buffer, or
+            // leaves it to its own event loop if it cannot right now.
+            if let Err(err) = target.push() {
+                self.on_error(target, err);
+            }
+
+            if filled == 0 || pipe.len() > 0 { break Ok(()); }
+        };
+
+        debug!("Spliced {} bytes from {:?} to {:?}", read, conn, target);
+        self.record_recv(conn, read);
+        match r {
+            Ok(_) => self.epoll_rearm(conn),
+            Err(err) => self.on_error(conn, err)
+        }
+    }
+
+    /// Calls `on_recv`, with `conn` corked for the duration of the call if
+    /// the loop is configured to, so whatever the handler sends in response
+    /// is written at once when it returns.
+    fn dispatch_recv(&self, conn: &Connection) {
+        if !self.config.cork_on_recv { return self.deliver(conn); }
+
+        if let Err(err) = conn.cork() { return self.on_error(conn, err); }
+        self.deliver(conn);
+        if let Err(err) = conn.uncork() { self.on_error(conn, err); }
+    }
+
+    /// Calls `on_message` with every complete frame if `conn` has a codec,
+    /// otherwise `on_recv`.
+    fn deliver(&self, conn: &Connection) {
+        if conn.codec().is_none() { return self.on_recv(conn); }
+
+        loop {
+            match conn.recv_frame() {
+                Ok(Some(frame)) => self.on_message(conn, frame),
+                Ok(None) => return,
+                Err(err) => {
+                    // Nothing after the undecodable bytes can be framed.
+                    self.on_error(conn, err);
+                    let _ = conn.shutdown();
+                    return;
                 }
             }
-            Err(err) => super::on_error(conn, err)
-        },
-        None => warn!("Unable to retrieve socket from map")
+
+            // The handler may have closed the connection, or taken its
+            // codec away, in response to the last frame.
+            if conn.state() != State::Open || conn.codec().is_none() { return; }
+        }
     }
-}
 
-fn epfd() -> RawFd { unsafe { EPFD } }
+    fn handle_write_event(&self, e: &epoll::Event) {
+        let fd = e.data() as RawFd;
+        match self.map_get(fd) {
+            Some(conn) => match conn.flush() {
+                Ok((sent, _)) => {
+                    debug!("Sent {} bytes to {:?}", sent, conn);
+                    self.record_send(&conn, sent);
+                    self.epoll_rearm(&conn);
+                    self.report_tx_progress(&conn);
+                }
+                Err(err) => self.on_error(&conn, err)
+            },
+            None => warn!("Unable to retrieve socket from map")
+        }
+    }
 
-fn epoll_rearm_r(fd: RawFd) {
-    let e = epoll::Event::new(epoll_events_r(), fd as u64);
-    let _ = epoll_mod(e).map_err(|err| {
-        error!("During rearm_r {}", err);
-    });
-}
+    /// Handles events for a connection that is flushing its transmit buffer
+    /// or waiting on the peer to close after a half-close.
+    fn handle_drain_event(&self, e: &epoll::Event) {
+        let fd = e.data() as RawFd;
+        let conn = match self.map_get(fd) {
+            Some(conn) => conn,
+            None => return
+        };
+
+        // Only EPOLLERR and EPOLLHUP are reported while flushing, either
+        // means there is no one left to flush to. While lingering,
+        // EPOLLRDHUP is the close we have been waiting for.
+        if close_event(e.events()) {
+            let _ = conn.shutdown();
+            return;
+        }
+
+        if read_event(e.events()) {
+            match socket::discard(fd) {
+                Ok(_) => self.epoll_rearm(&conn),
+                Err(_) => { let _ = conn.shutdown(); }
+            }
+            return;
+        }
+
+        if write_event(e.events()) {
+            match conn.flush() {
+                Ok((sent, true)) => {
+                    self.record_send(&conn, sent);
+                    self.epoll_rearm(&conn);
+                    self.report_sent_files(&conn);
+                }
+                Ok((sent, false)) => {
+                    self.record_send(&conn, sent);
+                    self.half_close(&conn);
+                    self.report_sent_files(&conn);
+                }
+                Err(_) => { let _ = conn.shutdown(); }
+            }
+        }
+    }
+
+    /// Shuts down the transmit side of a fully flushed connection, then waits
+    /// for the peer to close its side so that unread data on our end does
+    /// not cause a reset that could discard what was just sent.
+    fn half_close(&self, conn: &Connection) {
+        debug!("Half-closing {:?}", conn);
+        conn.set_state(State::Lingering);
+        match conn.shutdown_write() {
+            Ok(_) => self.epoll_rearm(conn),
+            Err(_) => { let _ = conn.shutdown(); }
+        }
+    }
+
+    fn record_recv(&self, conn: &Connection, read: usize) {
+        if read == 0 { return; }
+        self.counters.bytes_recv.fetch_add(read, Ordering::Relaxed);
+        conn.record_recv();
+    }
 
-fn epoll_rearm_rw(fd: RawFd) {
-    let e = epoll::Event::new(epoll_events_rw(), fd as u64);
-    let _ = epoll_mod(e).map_err(|err| {
-        error!("During rearm_rw {}", err);
-    });
+    pub fn record_send(&self, conn: &Connection, sent: usize) {
+        if sent == 0 { return; }
+        self.counters.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        conn.record_send();
+    }
+
+    fn epoll_rearm(&self, conn: &Connection) {
+        let _ = self.rearm(conn).map_err(|err| {
+            error!("During rearm {}", err);
+        });
+    }
+
+    /// Re-arms `conn` with the events appropriate for its current state.
+    fn rearm(&self, conn: &Connection) -> io::Result<()> {
+        // Other threads re-arm connections as they send to them, the
+        // interest lock keeps two re-arms from racing each other and leaving
+        // the connection armed for a stale set of events.
+        let _guard = conn.lock_interest();
+        let events = epoll_events(conn);
+        let e = epoll::Event::new(events, conn.socket as u64);
+        self.epoll_mod(e)
+    }
+
+    fn epoll_add(&self, e: epoll::Event) -> io::Result<()> {
+        self.epoll_ctl(EPOLL_CTL_ADD, e)
+    }
+
+    fn epoll_del(&self, e: epoll::Event) -> io::Result<()> {
+        self.epoll_ctl(EPOLL_CTL_DEL, e)
+    }
+
+    fn epoll_mod(&self, e: epoll::Event) -> io::Result<()> {
+        self.epoll_ctl(EPOLL_CTL_MOD, e)
+    }
+
+    fn epoll_ctl(&self, op: epoll::ControlOptions, e: epoll::Event) -> io::Result<()> {
+        epoll::ctl(self.epfd, op, e.data() as RawFd, e)
+    }
+
+    fn map_add(&self, c: Connection) {
+        let mut map = self.conns.lock();
+        map.insert(c.socket, c);
+    }
+
+    fn map_del(&self, c: &Connection) {
+        self.cancel_conn_timer(c);
+
+        let mut map = self.conns.lock();
+        map.remove(&c.socket);
+        if map.is_empty() { self.conns_empty.notify_all(); }
+    }
+
+    fn map_get(&self, fd: RawFd) -> Option<Connection> {
+        let map = self.conns.lock();
+        map.get(&fd).cloned()
+    }
 }
 
-fn epoll_events_r() -> epoll::Events {
-    EPOLLET | EPOLLONESHOT | EPOLLIN | EPOLLRDHUP
+impl LoopHandle {
+    pub(crate) fn new(ev_loop: Weak<EventLoop>) -> LoopHandle {
+        LoopHandle { ev_loop }
+    }
+
+    /// Runs `f` on the loop's thread once `after` has elapsed.
+    pub fn set_timeout<F>(&self, after: Duration, f: F) -> io::Result<TimerId>
+        where F: FnOnce() + Send + 'static
+    {
+        let ev_loop = self.ev_loop()?;
+        Ok(ev_loop.set_timeout(after, f))
+    }
+
+    /// Runs `f` on the loop's thread every `every` until cancelled.
+    pub fn set_interval<F>(&self, every: Duration, f: F) -> io::Result<TimerId>
+        where F: FnMut() + Send + 'static
+    {
+        let ev_loop = self.ev_loop()?;
+        Ok(ev_loop.set_interval(every, f))
+    }
+
+    /// Cancels a timer scheduled with `set_timeout` or `set_interval`,
+    /// returning false if it had already fired or been cancelled.
+    ///
+    /// A timeout has fired as soon as its callback starts running. An
+    /// interval can be cancelled while its callback runs, even from within
+    /// it, and is then not run again.
+    pub fn cancel_timer(&self, id: TimerId) -> bool {
+        match self.ev_loop.upgrade() {
+            Some(ev_loop) => ev_loop.cancel_timer(id),
+            None => false
+        }
+    }
+
+    fn ev_loop(&self) -> io::Result<Arc<EventLoop>> {
+        match self.ev_loop.upgrade() {
+            Some(ev_loop) => Ok(ev_loop),
+            None => Err(Error::new(ErrorKind::NotConnected, "Event loop stopped"))
+        }
+    }
 }
 
-fn epoll_events_rw() -> epoll::Events {
-    EPOLLET | EPOLLONESHOT | EPOLLIN | EPOLLOUT | EPOLLRDHUP
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        // Anything added after the loop thread's teardown is closed like
+        // any other connection, except that `shutdown` can no longer reach
+        // this loop to tell the handler.
+        let conns = mem::take(&mut *self.conns.lock());
+        for (_, conn) in conns {
+            if conn.state() == State::Closed { continue; }
+            let _ = conn.shutdown();
+            self.on_close(&conn);
+        }
+
+        // Never announced, so the handler is not told about these at all.
+        for conn in self.pending.lock().drain(..) { conn.abandon(); }
+
+        let _ = socket::close(self.wake_fd);
+        let _ = socket::close(self.timer_fd);
+        let _ = socket::close(self.epfd);
+    }
 }
 
-fn epoll_add(e: epoll::Event) -> io::Result<()> {
-    epoll_ctl(EPOLL_CTL_ADD, e)
+fn handle_listener_event(listener: &Listener, e: &epoll::Event) {
+    if socket_error(e.events()) {
+        let err = match socket::get_last_error(listener.fd()) {
+            Some(err) => err,
+            None => Error::other("Unknown SocketError")
+        };
+        listener.on_error(err);
+    } else {
+        listener.accept();
+    }
 }
 
-fn epoll_del(e: epoll::Event) -> io::Result<()> {
-    epoll_ctl(EPOLL_CTL_DEL, e)
+fn epoll_events(conn: &Connection) -> epoll::Events {
+    let write = conn.wants_write();
+    match conn.state() {
+        State::Flushing => EPOLLET | EPOLLONESHOT | EPOLLOUT,
+        State::Lingering => EPOLLET | EPOLLONESHOT | EPOLLIN | EPOLLRDHUP,
+        _ if conn.is_handshaking() && conn.handshake_wants_write() => {
+            EPOLLET | EPOLLONESHOT | EPOLLOUT | EPOLLRDHUP
+        }
+        _ if conn.is_handshaking() => epoll_events_r(),
+        // While not reading the peer closing its side is left to be found
+        // by the read that follows resuming, after any data sent before it.
+        _ if !conn.wants_read() && write => EPOLLET | EPOLLONESHOT | EPOLLOUT,
+        _ if !conn.wants_read() => EPOLLET | EPOLLONESHOT,
+        _ if write => epoll_events_rw(),
+        _ => epoll_events_r()
+    }
 }
 
-fn epoll_mod(e: epoll::Event) -> io::Result<()> {
-    epoll_ctl(EPOLL_CTL_MOD, e)
+fn epoll_events_r() -> epoll::Events {
+    EPOLLET | EPOLLONESHOT | EPOLLIN | EPOLLRDHUP
 }
 
-fn epoll_ctl(op: epoll::ControlOptions, e: epoll::Event) -> io::Result<()> {
-    epoll::ctl(epfd(), op, e.data() as RawFd, e)
+fn epoll_events_rw() -> epoll::Events {
+    EPOLLET | EPOLLONESHOT | EPOLLIN | EPOLLOUT | EPOLLRDHUP
 }
 
 fn close_event(e: Events) -> bool {
@@ -210,24 +1175,6 @@ fn write_event(e: Events) -> bool {
     (e & EPOLLOUT).bits() > 0
 }
 
-fn map_add(c: Connection) {
-    let mut map = (*CONN_MAP).lock();
-    map.insert(c.socket, c);
-}
-
-fn map_del(c: Connection) {
-    let mut map = (*CONN_MAP).lock();
-    map.remove(&c.socket);
-}
-
-fn map_get(fd: RawFd) -> Option<Connection> {
-    let map = (*CONN_MAP).lock();
-    match map.get(&fd) {
-        Some(c) => Some(*c),
-        None => None
-    }
-}
-
 fn socket_error(e: Events) -> bool {
     (e & EPOLLERR).bits() > 0
 }
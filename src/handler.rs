@@ -0,0 +1,314 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+#[cfg(feature = "ssl")]
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+use conn::Connection;
+
+
+/// Boxed closure called with a connection.
+pub type ConnFn = Box<dyn Fn(&Connection) + Send + Sync>;
+
+/// Boxed closure called with a connection and the error that occurred.
+pub type ErrorFn = Box<dyn Fn(&Connection, io::Error) + Send + Sync>;
+
+/// Boxed closure called with a connection and a file it has finished
+/// sending.
+pub type FileFn = Box<dyn Fn(&Connection, File) + Send + Sync>;
+
+/// Boxed closure called with a connection and a frame decoded from it.
+pub type MessageFn = Box<dyn Fn(&Connection, Vec<u8>) + Send + Sync>;
+
+/// Boxed closure called with an error that occurred on a listening socket.
+pub type ListenerErrorFn = Box<dyn Fn(io::Error) + Send + Sync>;
+
+
+/// Set of callbacks invoked by the event loop during the lifetime of
+/// a connection.
+///
+/// Every method has an empty default implementation, so implementors only
+/// need to override the events they care about. Because the handler is
+/// owned by alnio, any state it carries (pools, configs, routing tables)
+/// is available to every callback without resorting to globals.
+pub trait Handler: Send + Sync {
+    /// Called every time a new connection has been established.
+    fn on_connect(&self, _conn: &Connection) { }
+
+    /// Called every time there is new data available in the connection's
+    /// receive buffer, unless the connection has a codec.
+    fn on_recv(&self, _conn: &Connection) { }
+
+    /// Called with every complete frame received on a connection that has
+    /// a codec, in order, see `Codec`.
+    fn on_message(&self, _conn: &Connection, _frame: Vec<u8>) { }
+
+    /// Called every time an error has occurred for the connection.
+    fn on_error(&self, _conn: &Connection, _err: io::Error) { }
+
+    /// Called once the connection has been shutdown and its socket closed.
+    fn on_close(&self, _conn: &Connection) { }
+
+    /// Called when the connection's transmit buffer has gone past its
+    /// high watermark, producers should stop sending until `on_tx_drained`.
+    ///
+    /// Called from the sending thread before the send that went past the
+    /// watermark returns.
+    fn on_tx_high_watermark(&self, _conn: &Connection) { }
+
+    /// Called when a transmit buffer that went past its high watermark has
+    /// drained down to its low watermark.
+    ///
+    /// Called from whichever thread wrote to the socket, which is the
+    /// sending thread when a send or `uncork` writes straight through.
+    fn on_tx_drained(&self, _conn: &Connection) { }
+
+    /// Called with the file passed to `Connection::send_file` once all of
+    /// its region has been sent, from whichever thread finished sending it.
+    /// Files still queued when the connection closes are dropped.
+    fn on_file_sent(&self, _conn: &Connection, _file: File) { }
+
+    /// Called when the listening socket itself reports an error, rather
+    /// than one of the connections accepted from it.
+    fn on_listener_error(&self, _err: io::Error) { }
+}
+
+impl<H: Handler + ?Sized> Handler for Box<H> {
+    fn on_connect(&self, conn: &Connection) { (**self).on_connect(conn) }
+    fn on_recv(&self, conn: &Connection) { (**self).on_recv(conn) }
+    fn on_error(&self, conn: &Connection, err: io::Error) {
+        (**self).on_error(conn, err)
+    }
+    fn on_close(&self, conn: &Connection) { (**self).on_close(conn) }
+    fn on_tx_high_watermark(&self, conn: &Connection) {
+        (**self).on_tx_high_watermark(conn)
+    }
+    fn on_tx_drained(&self, conn: &Connection) { (**self).on_tx_drained(conn) }
+    fn on_file_sent(&self, conn: &Connection, file: File) {
+        (**self).on_file_sent(conn, file)
+    }
+    fn on_message(&self, conn: &Connection, frame: Vec<u8>) {
+        (**self).on_message(conn, frame)
+    }
+    fn on_listener_error(&self, err: io::Error) {
+        (**self).on_listener_error(err)
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn on_connect(&self, conn: &Connection) { (**self).on_connect(conn) }
+    fn on_recv(&self, conn: &Connection) { (**self).on_recv(conn) }
+    fn on_error(&self, conn: &Connection, err: io::Error) {
+        (**self).on_error(conn, err)
+    }
+    fn on_close(&self, conn: &Connection) { (**self).on_close(conn) }
+    fn on_tx_high_watermark(&self, conn: &Connection) {
+        (**self).on_tx_high_watermark(conn)
+    }
+    fn on_tx_drained(&self, conn: &Connection) { (**self).on_tx_drained(conn) }
+    fn on_file_sent(&self, conn: &Connection, file: File) {
+        (**self).on_file_sent(conn, file)
+    }
+    fn on_message(&self, conn: &Connection, frame: Vec<u8>) {
+        (**self).on_message(conn, frame)
+    }
+    fn on_listener_error(&self, err: io::Error) {
+        (**self).on_listener_error(err)
+    }
+}
+
+
+/// Forwards the events of every connection to the handler registered for
+/// the application protocol it negotiated with ALPN, or to the default
+/// handler if there is none.
+#[cfg(feature = "ssl")]
+pub struct ProtocolRouter {
+    default: Arc<dyn Handler>,
+    protocols: HashMap<Vec<u8>, Arc<dyn Handler>>
+}
+
+#[cfg(feature = "ssl")]
+impl ProtocolRouter {
+    pub fn new(default: Arc<dyn Handler>,
+               protocols: HashMap<Vec<u8>, Arc<dyn Handler>>) -> ProtocolRouter
+    {
+        ProtocolRouter { default, protocols }
+    }
+
+    fn route(&self, conn: &Connection) -> &dyn Handler {
+        let handler = conn.with_alpn_protocol(|p| p.and_then(|p| self.protocols.get(p)));
+        match handler {
+            Some(handler) => &**handler,
+            None => &*self.default
+        }
+    }
+}
+
+#[cfg(feature = "ssl")]
+impl Handler for ProtocolRouter {
+    fn on_connect(&self, conn: &Connection) { self.route(conn).on_connect(conn) }
+    fn on_recv(&self, conn: &Connection) { self.route(conn).on_recv(conn) }
+    fn on_error(&self, conn: &Connection, err: io::Error) {
+        self.route(conn).on_error(conn, err)
+    }
+    fn on_close(&self, conn: &Connection) { self.route(conn).on_close(conn) }
+    fn on_tx_high_watermark(&self, conn: &Connection) {
+        self.route(conn).on_tx_high_watermark(conn)
+    }
+    fn on_tx_drained(&self, conn: &Connection) { self.route(conn).on_tx_drained(conn) }
+    fn on_file_sent(&self, conn: &Connection, file: File) {
+        self.route(conn).on_file_sent(conn, file)
+    }
+    fn on_message(&self, conn: &Connection, frame: Vec<u8>) {
+        self.route(conn).on_message(conn, frame)
+    }
+    fn on_listener_error(&self, err: io::Error) {
+        self.default.on_listener_error(err)
+    }
+}
+
+
+/// A `Handler` built out of individual closures.
+///
+/// Any callback left unset is a no-op.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    on_connect: Option<Arc<ConnFn>>,
+    on_recv: Option<Arc<ConnFn>>,
+    on_error: Option<Arc<ErrorFn>>,
+    on_close: Option<Arc<ConnFn>>,
+    on_tx_high_watermark: Option<Arc<ConnFn>>,
+    on_tx_drained: Option<Arc<ConnFn>>,
+    on_file_sent: Option<Arc<FileFn>>,
+    on_message: Option<Arc<MessageFn>>,
+    on_listener_error: Option<Arc<ListenerErrorFn>>
+}
+
+impl Callbacks {
+    /// Creates a new Callbacks with no callbacks set.
+    pub fn new() -> Callbacks { Callbacks::default() }
+
+    /// Sets the closure called every time a new connection has been
+    /// established.
+    pub fn on_connect<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection) + Send + Sync + 'static
+    {
+        self.on_connect = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called every time there is new data available
+    /// from a connection.
+    pub fn on_recv<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection) + Send + Sync + 'static
+    {
+        self.on_recv = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called with every frame decoded from a connection
+    /// that has a codec.
+    pub fn on_message<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection, Vec<u8>) + Send + Sync + 'static
+    {
+        self.on_message = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called every time an error has occurred for
+    /// a connection.
+    pub fn on_error<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection, io::Error) + Send + Sync + 'static
+    {
+        self.on_error = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called once a connection has been closed.
+    pub fn on_close<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection) + Send + Sync + 'static
+    {
+        self.on_close = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called when a connection's transmit buffer has gone
+    /// past its high watermark.
+    pub fn on_tx_high_watermark<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection) + Send + Sync + 'static
+    {
+        self.on_tx_high_watermark = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called when a connection's transmit buffer has
+    /// drained down to its low watermark.
+    pub fn on_tx_drained<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection) + Send + Sync + 'static
+    {
+        self.on_tx_drained = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called when a connection has finished sending a
+    /// file queued with `Connection::send_file`.
+    pub fn on_file_sent<F>(mut self, f: F) -> Callbacks
+        where F: Fn(&Connection, File) + Send + Sync + 'static
+    {
+        self.on_file_sent = Some(Arc::new(Box::new(f)));
+        self
+    }
+
+    /// Sets the closure called when a listening socket reports an error.
+    pub fn on_listener_error<F>(mut self, f: F) -> Callbacks
+        where F: Fn(io::Error) + Send + Sync + 'static
+    {
+        self.on_listener_error = Some(Arc::new(Box::new(f)));
+        self
+    }
+}
+
+impl Handler for Callbacks {
+    fn on_connect(&self, conn: &Connection) {
+        if let Some(ref f) = self.on_connect { f(conn); }
+    }
+
+    fn on_recv(&self, conn: &Connection) {
+        if let Some(ref f) = self.on_recv { f(conn); }
+    }
+
+    fn on_error(&self, conn: &Connection, err: io::Error) {
+        if let Some(ref f) = self.on_error { f(conn, err); }
+    }
+
+    fn on_close(&self, conn: &Connection) {
+        if let Some(ref f) = self.on_close { f(conn); }
+    }
+
+    fn on_tx_high_watermark(&self, conn: &Connection) {
+        if let Some(ref f) = self.on_tx_high_watermark { f(conn); }
+    }
+
+    fn on_tx_drained(&self, conn: &Connection) {
+        if let Some(ref f) = self.on_tx_drained { f(conn); }
+    }
+
+    fn on_file_sent(&self, conn: &Connection, file: File) {
+        if let Some(ref f) = self.on_file_sent { f(conn, file); }
+    }
+
+    fn on_message(&self, conn: &Connection, frame: Vec<u8>) {
+        if let Some(ref f) = self.on_message { f(conn, frame); }
+    }
+
+    fn on_listener_error(&self, err: io::Error) {
+        if let Some(ref f) = self.on_listener_error { f(err); }
+    }
+}
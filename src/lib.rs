@@ -10,127 +10,179 @@ extern crate epoll;
 extern crate libc;
 #[macro_use] extern crate log;
 extern crate parking_lot;
+#[cfg(feature = "ssl")] extern crate openssl;
 
 
+use std::fs::File;
 use std::io;
-use std::net::{TcpListener, ToSocketAddrs};
-use std::os::unix::io::IntoRawFd;
-
-pub use conn::Connection;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+pub use buf::Shrink;
+pub use codec::{
+    Codec,
+    Decoder,
+    Encoder,
+    Endian,
+    LengthPrefixed,
+    Lines,
+    PrefixWidth,
+    Varint,
+    DEFAULT_MAX_FRAME_LEN
+};
+pub use conn::{Connection, Timeout, Timeouts, TxPolicy};
+pub use handler::{
+    Callbacks,
+    ConnFn,
+    ErrorFn,
+    FileFn,
+    Handler,
+    ListenerErrorFn,
+    MessageFn
+};
+pub use event_loop::{LoopHandle, LoopStats};
+pub use server::{Balance, Builder, Server};
+#[cfg(feature = "ssl")]
+pub use conn::SubjectAltName;
+#[cfg(feature = "ssl")]
+pub use sslcfg::{SslConfig, TlsVersion, VerifyClient};
+pub use timer::TimerId;
 
 mod buf;
+mod codec;
 mod conn;
 mod event_loop;
+mod handler;
+mod listener;
+mod pipe;
+mod server;
 mod socket;
+#[cfg(feature = "ssl")]
+mod sslcfg;
+mod timer;
+mod zerocopy;
 
 
-/// on_connect handler
-static mut ON_CONNECT_OPT: Option<fn(&Connection)> = None;
-
-/// on_recv handler
-static mut ON_NEW_DATA_OPT: Option<fn(&Connection)> = None;
+lazy_static! {
+    /// Closures registered through the `register_on_*` functions since
+    /// the last call to `register_handler`.
+    static ref CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks::new());
 
-/// on_close handler
-static mut ON_ERROR_OPT: Option<fn(&Connection, io::Error)> = None;
+    /// Handler used by servers started without one of their own.
+    static ref HANDLER: Mutex<Arc<dyn Handler>> = {
+        Mutex::new(Arc::new(Callbacks::new()))
+    };
+}
 
 
 /// Registers a handler to be called every time a new connection has
 /// been established.
-pub fn register_on_connect(h: fn(conn: &Connection)) {
-    unsafe { ON_CONNECT_OPT = Some(h); }
+pub fn register_on_connect<F>(h: F)
+    where F: Fn(&Connection) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_connect(h));
 }
 
 /// Registers a handler to be called every time there is new data available
 /// from the passed connection.
-pub fn register_on_recv(h: fn(conn: &Connection)) {
-    unsafe { ON_NEW_DATA_OPT = Some(h); }
+pub fn register_on_recv<F>(h: F)
+    where F: Fn(&Connection) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_recv(h));
+}
+
+/// Registers a handler to be called with every frame decoded from
+/// a connection that has a codec.
+pub fn register_on_message<F>(h: F)
+    where F: Fn(&Connection, Vec<u8>) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_message(h));
 }
 
 /// Registers a handler to be called every time an error has occurred for
 /// the connection.
-pub fn register_on_error(h: fn(conn: &Connection, err: io::Error)) {
-    unsafe { ON_ERROR_OPT = Some(h); }
+pub fn register_on_error<F>(h: F)
+    where F: Fn(&Connection, io::Error) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_error(h));
 }
 
-/// Starts the server and binds to the passed address.
-///
-/// A port number of 0 will request that the OS assigns a port.
-pub fn start<A: ToSocketAddrs>(addr: A) {
-    let listener_result = TcpListener::bind(addr);
-    if listener_result.is_err() {
-        let err = listener_result.unwrap_err();
-        error!("{} during bind.", err);
-        return;
-    }
-
-    let tcp_listener = listener_result.unwrap();
-    info!("Bound to {}", tcp_listener.local_addr().unwrap());
-
-    let _ = event_loop::init().map_err(|e| {
-        panic!("{} during epoll creattion", e)
-    });
-
-    loop { accept_connection(&tcp_listener); }
+/// Registers a handler to be called every time a connection has been
+/// closed.
+pub fn register_on_close<F>(h: F)
+    where F: Fn(&Connection) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_close(h));
+}
+
+/// Registers a handler to be called every time a connection's transmit
+/// buffer goes past its high watermark.
+pub fn register_on_tx_high_watermark<F>(h: F)
+    where F: Fn(&Connection) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_tx_high_watermark(h));
 }
 
-fn accept_connection(tcp_listener: &TcpListener) {
-    match tcp_listener.accept() {
-        Ok((tcp_stream, addr)) => {
-            match tcp_stream.set_nonblocking(true) {
-                Ok(_) => on_new_connection(Connection {
-                    socket: tcp_stream.into_raw_fd(),
-                    addr: addr
-                }),
-                Err(err) => error!("Setting TcpStream nonblocking: {}", err)
-            }
-        }
-        Err(e) => {
-            // We need to figure out if the listener has
-            // errord out or if it was an error on the part
-            // of the connecting stream
-            let maybe_err = tcp_listener.take_error().unwrap();
-            if maybe_err.is_some() {
-                let err = maybe_err.unwrap();
-                // The listener itself has errord out, we would close up shop.
-                error!("Listener SO_ERROR: {} with {} during accept", err, e);
-                panic!();
-            }
-        }
-    }
+/// Registers a handler to be called every time a connection's transmit
+/// buffer drains down to its low watermark.
+pub fn register_on_tx_drained<F>(h: F)
+    where F: Fn(&Connection) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_tx_drained(h));
 }
 
-fn on_new_connection(conn: Connection) {
-    info!("New connection: {:?}", conn);
+/// Registers a handler to be called every time a connection has finished
+/// sending a file queued with `Connection::send_file`.
+pub fn register_on_file_sent<F>(h: F)
+    where F: Fn(&Connection, File) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_file_sent(h));
+}
 
-    socket::init(conn.socket);
-    let _ = event_loop::add_conn(conn).map_err(|e| {
-        warn!("During epoll add {}", e);
-    });
+/// Registers a handler to be called every time a listening socket reports
+/// an error.
+pub fn register_on_listener_error<F>(h: F)
+    where F: Fn(io::Error) + Send + Sync + 'static
+{
+    update_callbacks(|cbs| cbs.on_listener_error(h));
+}
 
-    unsafe {
-        if ON_CONNECT_OPT.is_some() {
-            let f = ON_CONNECT_OPT.as_ref().unwrap();
-            f(&conn);
-        }
-    }
+/// Registers `h` to receive every connection event, replacing any
+/// previously registered handler or closures.
+///
+/// Handlers and closures do not mix, whichever was registered last wins.
+/// Registering a closure through any of the `register_on_*` functions
+/// after this replaces `h` in turn, with only that closure and those
+/// registered after it.
+pub fn register_handler<H: Handler + 'static>(h: H) {
+    let mut cbs = (*CALLBACKS).lock();
+    *cbs = Callbacks::new();
+    *(*HANDLER).lock() = Arc::new(h);
 }
 
-fn on_recv(conn: Connection) {
-    unsafe {
-        if ON_NEW_DATA_OPT.is_some() {
-            let f = ON_NEW_DATA_OPT.as_ref().unwrap();
-            f(&conn);
-        }
-    }
+/// Starts a server bound to the passed address with the default
+/// configuration, dispatching to the handler registered through the
+/// `register_*` functions, and returns a handle to it.
+///
+/// A port number of 0 will request that the OS assigns a port.
+pub fn start<A: ToSocketAddrs>(addr: A) -> io::Result<Server> {
+    Builder::new().start(addr)
 }
 
-fn on_error(conn: Connection, err: io::Error) {
-    debug!("Connection {:?} error: {}", conn, err);
+/// Applies `f` to the registered closures and makes the result the
+/// active handler.
+fn update_callbacks<F>(f: F) where F: FnOnce(Callbacks) -> Callbacks {
+    let mut cbs = (*CALLBACKS).lock();
+    *cbs = f(cbs.clone());
+    *(*HANDLER).lock() = Arc::new(cbs.clone());
+}
 
-    unsafe {
-        if ON_ERROR_OPT.is_some() {
-            let f = ON_ERROR_OPT.as_ref().unwrap();
-            f(&conn, err);
-        }
-    }
+/// Returns the registered handler.
+///
+/// Servers take a snapshot of it when started, so registering after a
+/// server has started does not affect that server.
+fn handler() -> Arc<dyn Handler> {
+    (*HANDLER).lock().clone()
 }
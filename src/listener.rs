@@ -0,0 +1,185 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslContext;
+
+use conn::{Connection, TcpTransport, Transport, UnixTransport};
+#[cfg(feature = "ssl")]
+use conn::TlsTransport;
+use event_loop::EventLoop;
+use handler::Handler;
+use server::Balance;
+use socket;
+
+
+/// A nonblocking listening socket registered with one of a server's event
+/// loops, handing the connections it accepts to every loop in the pool.
+pub struct Listener {
+    socket: ListenSocket,
+    ev_loops: Vec<Weak<EventLoop>>,
+    balance: Balance,
+    next: AtomicUsize,
+    handler: Arc<dyn Handler>,
+    /// Context TLS is terminated with, if set.
+    #[cfg(feature = "ssl")]
+    ssl: Option<SslContext>
+}
+
+/// Kinds of socket connections can be accepted on, each accepting
+/// connections carried over its own `Transport`.
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+impl Listener {
+    /// Creates a new Listener distributing connections across `ev_loops`.
+    pub fn new(socket: ListenSocket,
+               ev_loops: &[Arc<EventLoop>],
+               balance: Balance,
+               handler: Arc<dyn Handler>) -> io::Result<Listener>
+    {
+        match socket {
+            ListenSocket::Tcp(ref l) => l.set_nonblocking(true)?,
+            ListenSocket::Unix(ref l) => l.set_nonblocking(true)?
+        }
+
+        Ok(Listener {
+            socket,
+            ev_loops: ev_loops.iter().map(Arc::downgrade).collect(),
+            balance,
+            next: AtomicUsize::new(0),
+            handler,
+            #[cfg(feature = "ssl")]
+            ssl: None
+        })
+    }
+
+    /// Terminates TLS on every connection accepted from now on with `ctx`.
+    #[cfg(feature = "ssl")]
+    pub fn set_ssl(&mut self, ctx: SslContext) {
+        self.ssl = Some(ctx);
+    }
+
+    pub fn fd(&self) -> RawFd {
+        match self.socket {
+            ListenSocket::Tcp(ref l) => l.as_raw_fd(),
+            ListenSocket::Unix(ref l) => l.as_raw_fd()
+        }
+    }
+
+    /// Accepts every pending connection until EAGAIN/EWOULDBLOCK is
+    /// received, as the listener is registered in Edge Triggered mode.
+    pub fn accept(&self) {
+        loop {
+            match socket::accept(self.fd()) {
+                Ok((fd, addr)) => self.on_new_connection(fd, addr),
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock { return; }
+
+                    // We need to figure out if the listener has
+                    // errored out or if it was an error on the part
+                    // of the connecting stream
+                    if let Some(so_err) = socket::get_last_error(self.fd()) {
+                        return self.on_error(so_err);
+                    }
+
+                    match err.raw_os_error() {
+                        Some(libc::EINTR) | Some(libc::ECONNABORTED) => continue,
+                        _ => return self.on_error(err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports an error on the listening socket itself.
+    pub fn on_error(&self, err: io::Error) {
+        error!("Listener error: {}", err);
+        self.handler.on_listener_error(err);
+    }
+
+    fn on_new_connection(&self, fd: RawFd, addr: SocketAddr) {
+        let ev_loop = match self.pick_loop() {
+            Some(ev_loop) => ev_loop,
+            None => {
+                let _ = socket::close(fd);
+                return;
+            }
+        };
+
+        let transport = match self.transport(fd) {
+            Ok(transport) => transport,
+            Err(e) => {
+                warn!("During transport setup {}", e);
+                let _ = socket::close(fd);
+                return;
+            }
+        };
+
+        let conn = Connection::new(transport,
+                                   addr,
+                                   ev_loop.config(),
+                                   Arc::downgrade(&ev_loop));
+
+        // Should the wake fail, the connection is registered whenever the
+        // loop next wakes.
+        if let Err(e) = ev_loop.adopt(conn) {
+            warn!("During hand off {}", e);
+        }
+    }
+
+    /// Creates the transport a connection accepted as `fd` is carried over.
+    fn transport(&self, fd: RawFd) -> io::Result<Box<dyn Transport>> {
+        let transport: Box<dyn Transport> = match self.socket {
+            ListenSocket::Tcp(_) => Box::new(TcpTransport::new(fd)),
+            ListenSocket::Unix(_) => Box::new(UnixTransport::new(fd))
+        };
+        self.secure(transport)
+    }
+
+    /// Layers TLS over `transport`, if this listener terminates it.
+    #[cfg(feature = "ssl")]
+    fn secure(&self, transport: Box<dyn Transport>) -> io::Result<Box<dyn Transport>> {
+        match self.ssl {
+            Some(ref ctx) => Ok(Box::new(TlsTransport::accept(ctx, transport)?)),
+            None => Ok(transport)
+        }
+    }
+
+    #[cfg(not(feature = "ssl"))]
+    fn secure(&self, transport: Box<dyn Transport>) -> io::Result<Box<dyn Transport>> {
+        Ok(transport)
+    }
+
+    fn pick_loop(&self) -> Option<Arc<EventLoop>> {
+        let ev_loops: Vec<Arc<EventLoop>> = self.ev_loops.iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+
+        if ev_loops.is_empty() { return None; }
+
+        match self.balance {
+            Balance::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                Some(ev_loops[next % ev_loops.len()].clone())
+            }
+            Balance::LeastLoaded => {
+                ev_loops.into_iter().min_by_key(|l| l.load())
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::io::{self, Error, ErrorKind};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use libc;
+use parking_lot::Mutex;
+
+use socket;
+
+
+/// Most bytes moved by a single call to splice, the default capacity of
+/// a pipe.
+const SPLICE_LEN: usize = 64 * 1024;
+
+
+/// A nonblocking kernel pipe that bytes are spliced through, from one
+/// socket to another, without ever being copied into userspace.
+pub struct Pipe {
+    read_fd: RawFd,
+    write_fd: RawFd,
+    /// Number of bytes sitting in the pipe. Held while splicing, so that
+    /// only one thread at a time moves bytes in or out.
+    len: Mutex<usize>
+}
+
+impl Pipe {
+    /// Creates a new nonblocking, close-on-exec pipe.
+    pub fn new() -> io::Result<Pipe> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        let flags = libc::O_NONBLOCK | libc::O_CLOEXEC;
+        let r = unsafe { libc::pipe2(fds.as_mut_ptr(), flags) };
+        if r == -1 { return Err(Error::last_os_error()); }
+
+        Ok(Pipe { read_fd: fds[0], write_fd: fds[1], len: Mutex::new(0) })
+    }
+
+    /// Returns the number of bytes sitting in the pipe.
+    pub fn len(&self) -> usize { *self.len.lock() }
+
+    /// Moves as much as is available from the socket `fd` into the pipe,
+    /// until either is exhausted. Returns 0 on EOF, and `WouldBlock` if
+    /// nothing could be moved.
+    pub fn fill(&self, fd: RawFd) -> io::Result<usize> {
+        let mut len = self.len.lock();
+        let r = splice(fd, self.write_fd)?;
+        *len += r;
+        Ok(r)
+    }
+
+    /// Moves everything in the pipe to the socket `fd` until
+    /// EAGAIN/EWOULDBLOCK is received.
+    ///
+    /// Returns the number of bytes moved, and whether anything is left in
+    /// the pipe.
+    pub fn drain(&self, fd: RawFd) -> io::Result<(usize, bool)> {
+        let mut len = self.len.lock();
+        let mut total_moved = 0;
+        while *len > 0 {
+            match splice(self.read_fd, fd) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "WriteZero")),
+                Ok(moved) => {
+                    *len -= moved;
+                    total_moved += moved;
+                }
+                Err(err) => {
+                    if err.kind() == ErrorKind::WouldBlock { break; }
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok((total_moved, *len > 0))
+    }
+
+    /// Reads everything left in the pipe into userspace, for when there is
+    /// no longer a socket to splice it to.
+    pub fn take_all(&self) -> io::Result<Vec<u8>> {
+        let mut len = self.len.lock();
+        let mut buf = vec![0u8; *len];
+        let mut num_read = 0;
+        while num_read < buf.len() {
+            let r = unsafe {
+                libc::read(self.read_fd,
+                           buf[num_read..].as_mut_ptr() as *mut libc::c_void,
+                           buf.len() - num_read)
+            };
+
+            if r == -1 {
+                let err = Error::last_os_error();
+                if err.kind() == ErrorKind::Interrupted { continue; }
+                return Err(err);
+            }
+            if r == 0 { break; }
+            num_read += r as usize;
+        }
+
+        *len -= num_read;
+        buf.truncate(num_read);
+        Ok(buf)
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        let _ = socket::close(self.read_fd);
+        let _ = socket::close(self.write_fd);
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+    loop {
+        let r = unsafe {
+            libc::splice(fd_in,
+                         ptr::null_mut(),
+                         fd_out,
+                         ptr::null_mut(),
+                         SPLICE_LEN,
+                         flags)
+        };
+
+        if r == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted { continue; }
+            return Err(err);
+        }
+        return Ok(r as usize);
+    }
+}
@@ -0,0 +1,449 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+#[cfg(feature = "ssl")]
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, ToSocketAddrs};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle, ThreadId};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use buf::Shrink;
+use codec::Codec;
+use conn::{Config, TxPolicy};
+use event_loop::{EventLoop, LoopHandle, LoopStats};
+use handler::Handler;
+#[cfg(feature = "ssl")]
+use handler::ProtocolRouter;
+use listener::{ListenSocket, Listener};
+#[cfg(feature = "ssl")]
+use sslcfg::SslConfig;
+
+
+/// Configures and starts a `Server`.
+pub struct Builder {
+    handler: Option<Arc<dyn Handler>>,
+    codec: Option<Arc<dyn Codec>>,
+    event_loops: usize,
+    balance: Balance,
+    config: Config,
+    #[cfg(feature = "ssl")]
+    ssl: Option<SslConfig>,
+    /// Handlers of connections that negotiated each ALPN protocol.
+    #[cfg(feature = "ssl")]
+    protocol_handlers: HashMap<String, Arc<dyn Handler>>
+}
+
+/// Strategy used to hand accepted connections to a server's event loops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    /// Each event loop receives the next connection in turn.
+    RoundRobin,
+    /// The event loop with the fewest connections receives the next
+    /// connection, counting those it has been handed but not registered
+    /// yet, so that a burst of connections is spread out too.
+    LeastLoaded
+}
+
+/// Handle to a running server.
+///
+/// Every server owns its own event loop and connections, so any number of
+/// them can run side by side in the same process.
+///
+/// Dropping the handle shuts the server down.
+pub struct Server {
+    addr: SocketAddr,
+    running: AtomicBool,
+    ev_loops: Mutex<Vec<Arc<EventLoop>>>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// Ids of the event loop threads, kept apart from `threads` so that
+    /// they can be checked while another thread is joining.
+    thread_ids: Vec<ThreadId>
+}
+
+
+impl Builder {
+    /// Creates a new Builder.
+    pub fn new() -> Builder {
+        Builder {
+            handler: None,
+            codec: None,
+            event_loops: 1,
+            balance: Balance::RoundRobin,
+            config: Config::default(),
+            #[cfg(feature = "ssl")]
+            ssl: None,
+            #[cfg(feature = "ssl")]
+            protocol_handlers: HashMap::new()
+        }
+    }
+
+    /// Sets the handler connection events are dispatched to.
+    ///
+    /// If unset, the handler registered through the `register_*` functions
+    /// at the time of `start` is used.
+    pub fn handler<H: Handler + 'static>(mut self, h: H) -> Builder {
+        self.handler = Some(Arc::new(h));
+        self
+    }
+
+    /// Sets the codec every connection's frames are decoded and encoded
+    /// with, delivering complete frames to `Handler::on_message`. See
+    /// `Connection::set_codec`. Defaults to none.
+    pub fn codec<C: Codec + 'static>(mut self, codec: C) -> Builder {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Sets the number of event loop threads, each with its own epoll
+    /// instance. Defaults to 1.
+    pub fn event_loops(mut self, n: usize) -> Builder {
+        self.event_loops = if n == 0 { 1 } else { n };
+        self
+    }
+
+    /// Sets how accepted connections are distributed across event loops.
+    /// Defaults to `Balance::RoundRobin`.
+    pub fn balance(mut self, balance: Balance) -> Builder {
+        self.balance = balance;
+        self
+    }
+
+    /// Sets the idle timeout applied to every connection, see
+    /// `Connection::set_idle_timeout`. Defaults to none.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Sets the read timeout applied to every connection, see
+    /// `Connection::set_read_timeout`. Defaults to none.
+    pub fn read_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Sets the write timeout applied to every connection, see
+    /// `Connection::set_write_timeout`. Defaults to none.
+    pub fn write_timeout(mut self, timeout: Duration) -> Builder {
+        self.config.timeouts.write = Some(timeout);
+        self
+    }
+
+    /// Sets the receive buffer high watermark applied to every connection,
+    /// see `Connection::set_rx_high_watermark`. Defaults to none.
+    pub fn rx_high_watermark(mut self, bytes: usize) -> Builder {
+        self.config.rx_high_watermark = Some(bytes);
+        self
+    }
+
+    /// Sets the transmit buffer high watermark applied to every connection,
+    /// see `Connection::set_tx_high_watermark`. Defaults to none.
+    pub fn tx_high_watermark(mut self, bytes: usize) -> Builder {
+        self.config.tx_high_watermark = Some(bytes);
+        self
+    }
+
+    /// Sets the transmit buffer low watermark applied to every connection,
+    /// see `Connection::set_tx_low_watermark`. Defaults to 0.
+    pub fn tx_low_watermark(mut self, bytes: usize) -> Builder {
+        self.config.tx_low_watermark = bytes;
+        self
+    }
+
+    /// Sets what happens to data sent past a connection's transmit buffer
+    /// high watermark. Defaults to `TxPolicy::Reject`.
+    pub fn tx_policy(mut self, policy: TxPolicy) -> Builder {
+        self.config.tx_policy = policy;
+        self
+    }
+
+    /// Sets when connection buffers give back storage they have grown to.
+    /// Defaults to `Shrink::WhenEmpty(65536)`.
+    pub fn buffer_shrink(mut self, shrink: Shrink) -> Builder {
+        self.config.shrink = shrink;
+        self
+    }
+
+    /// Corks every connection while `Handler::on_recv` runs, so everything
+    /// sent in response to the data received is written at once when the
+    /// handler returns, see `Connection::cork`. Defaults to false.
+    pub fn cork_on_recv(mut self, cork: bool) -> Builder {
+        self.config.cork_on_recv = cork;
+        self
+    }
+
+    /// Sets `TCP_CORK` on a connection's socket whenever it is corked, so
+    /// the kernel only sends full sized packets until it is uncorked.
+    /// Defaults to false.
+    pub fn tcp_cork(mut self, tcp_cork: bool) -> Builder {
+        self.config.tcp_cork = tcp_cork;
+        self
+    }
+
+    /// Sends payloads queued with `Connection::send_shared` that have at
+    /// least `min_len` bytes left to send zero-copy on every connection, see
+    /// `Connection::set_zerocopy`. Defaults to none.
+    pub fn zerocopy(mut self, min_len: usize) -> Builder {
+        self.config.zerocopy = Some(min_len);
+        self
+    }
+
+    /// Terminates TLS on every connection with `config`. Connections are
+    /// handed to `Handler::on_connect` once their handshake has completed,
+    /// after which everything received and sent is plaintext. A failed
+    /// handshake is reported to `Handler::on_error` and the connection is
+    /// shutdown. Defaults to none.
+    #[cfg(feature = "ssl")]
+    pub fn ssl(mut self, config: SslConfig) -> Builder {
+        self.ssl = Some(config);
+        self
+    }
+
+    /// Dispatches the events of connections that negotiated `protocol` with
+    /// ALPN to `h` instead of the server's handler, so that each protocol
+    /// served on the same port has its own set of callbacks. `protocol`
+    /// must also be offered with `SslConfig::alpn_protocols`.
+    ///
+    /// Connections that negotiated no protocol, or one without a handler
+    /// of its own, are dispatched to the server's handler, as are failed
+    /// handshakes and listener errors.
+    #[cfg(feature = "ssl")]
+    pub fn protocol_handler<H>(mut self, protocol: &str, h: H) -> Builder
+        where H: Handler + 'static
+    {
+        self.protocol_handlers.insert(protocol.to_owned(), Arc::new(h));
+        self
+    }
+
+    /// Binds to the passed address and starts the event loops, the first of
+    /// which accepts connections on the listening socket.
+    ///
+    /// A port number of 0 will request that the OS assigns a port.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> io::Result<Server> {
+        let tcp_listener = TcpListener::bind(addr).map_err(|e| {
+            error!("{} during bind.", e);
+            e
+        })?;
+
+        let addr = tcp_listener.local_addr()?;
+        info!("Bound to {}", addr);
+
+        self.run(ListenSocket::Tcp(tcp_listener), addr)
+    }
+
+    /// Binds a Unix-domain stream socket to `path` and starts the event
+    /// loops, the same as `start`. Connections' addresses, and the server's,
+    /// are the unspecified address `0.0.0.0:0`.
+    ///
+    /// Fails if something already exists at `path`, and the socket file is
+    /// left behind once the server shuts down.
+    pub fn start_unix<P: AsRef<Path>>(self, path: P) -> io::Result<Server> {
+        let unix_listener = UnixListener::bind(path.as_ref()).map_err(|e| {
+            error!("{} during bind.", e);
+            e
+        })?;
+        info!("Bound to {}", path.as_ref().display());
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0));
+        self.run(ListenSocket::Unix(unix_listener), addr)
+    }
+
+    fn run(self, socket: ListenSocket, addr: SocketAddr) -> io::Result<Server> {
+        let handler = self.handler.clone().unwrap_or_else(super::handler);
+        let handler = self.route_protocols(handler)?;
+        let mut ev_loops = Vec::with_capacity(self.event_loops);
+        for id in 0..self.event_loops {
+            let ev_loop = EventLoop::new(id,
+                                         handler.clone(),
+                                         self.codec.clone(),
+                                         self.config);
+            let ev_loop = ev_loop.map_err(|e| {
+                error!("{} during epoll creation", e);
+                e
+            })?;
+            ev_loops.push(Arc::new(ev_loop));
+        }
+
+        let mut listener = Listener::new(socket,
+                                              &ev_loops,
+                                              self.balance,
+                                              handler)?;
+        self.configure_ssl(&mut listener)?;
+        ev_loops[0].listen(listener)?;
+
+        let mut threads = Vec::with_capacity(ev_loops.len());
+        for ev_loop in ev_loops.iter() {
+            let ev_loop = ev_loop.clone();
+            threads.push(thread::spawn(move || ev_loop.run()));
+        }
+        let thread_ids = threads.iter().map(|t| t.thread().id()).collect();
+
+        Ok(Server {
+            addr,
+            running: AtomicBool::new(true),
+            ev_loops: Mutex::new(ev_loops),
+            threads: Mutex::new(threads),
+            thread_ids
+        })
+    }
+}
+
+impl Builder {
+    #[cfg(feature = "ssl")]
+    fn configure_ssl(&self, listener: &mut Listener) -> io::Result<()> {
+        if let Some(ref config) = self.ssl {
+            listener.set_ssl(config.build()?);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "ssl"))]
+    fn configure_ssl(&self, _listener: &mut Listener) -> io::Result<()> { Ok(()) }
+
+    /// Wraps `handler` so that connections reach the handler of the
+    /// protocol they negotiated, if any were registered.
+    #[cfg(feature = "ssl")]
+    fn route_protocols(&self, handler: Arc<dyn Handler>) -> io::Result<Arc<dyn Handler>> {
+        if self.protocol_handlers.is_empty() { return Ok(handler); }
+
+        let mut protocols = HashMap::with_capacity(self.protocol_handlers.len());
+        for (protocol, h) in self.protocol_handlers.iter() {
+            let offered = match self.ssl {
+                Some(ref config) => config.offers_protocol(protocol),
+                None => false
+            };
+            if !offered {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "Handled protocol not offered with ALPN"));
+            }
+            protocols.insert(protocol.as_bytes().to_vec(), h.clone());
+        }
+
+        Ok(Arc::new(ProtocolRouter::new(handler, protocols)))
+    }
+
+    #[cfg(not(feature = "ssl"))]
+    fn route_protocols(&self, handler: Arc<dyn Handler>) -> io::Result<Arc<dyn Handler>> {
+        Ok(handler)
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder { Builder::new() }
+}
+
+impl Server {
+    /// Returns the address this server is bound to.
+    pub fn local_addr(&self) -> SocketAddr { self.addr }
+
+    /// Returns a handle to each of this server's event loops.
+    pub fn event_loops(&self) -> Vec<LoopHandle> {
+        self.ev_loops.lock()
+            .iter()
+            .map(|l| LoopHandle::new(Arc::downgrade(l)))
+            .collect()
+    }
+
+    /// Returns a snapshot of each event loop's activity, indexed by loop.
+    pub fn stats(&self) -> Vec<LoopStats> {
+        self.ev_loops.lock().iter().map(|l| l.stats()).collect()
+    }
+
+    /// Stops accepting new connections and gracefully closes every existing
+    /// one, see `Connection::close_after_flush`, then shuts the server down.
+    ///
+    /// Connections that have not finished closing within `timeout` are
+    /// shutdown regardless.
+    ///
+    /// Waiting for the connections requires the event loops to keep
+    /// running, so this fails when called from one of them, for example
+    /// from a handler callback.
+    pub fn drain(&self, timeout: Duration) -> io::Result<()> {
+        if self.on_loop_thread() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                      "Server drained from its own event loop"));
+        }
+
+        let deadline = Instant::now() + timeout;
+        let ev_loops = self.ev_loops.lock().clone();
+
+        for ev_loop in ev_loops.iter() { ev_loop.stop_listening(); }
+        for ev_loop in ev_loops.iter() { ev_loop.drain(timeout)?; }
+        for ev_loop in ev_loops.iter() {
+            if !ev_loop.wait_drained(deadline) {
+                warn!("Drain deadline passed with connections still open");
+            }
+        }
+
+        self.shutdown()
+    }
+
+    /// Stops accepting new connections, shuts down all existing ones,
+    /// closes the epoll fds and waits for the server's threads to exit.
+    ///
+    /// When called from one of the server's event loops, for example from
+    /// a handler callback, the loops are only signalled to stop, each
+    /// exits once its current callback returns.
+    pub fn shutdown(&self) -> io::Result<()> {
+        let mut result = Ok(());
+        if self.running.swap(false, Ordering::SeqCst) {
+            for ev_loop in self.ev_loops.lock().iter() {
+                if let Err(e) = ev_loop.shutdown() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        self.join();
+
+        // Once every thread is gone, these are the last strong references
+        // to the event loops, dropping them closes the epoll fds. A loop
+        // thread still running holds its own, closing its fds as it exits.
+        self.ev_loops.lock().clear();
+
+        result
+    }
+
+    /// Blocks until the server has been shutdown and all of its threads
+    /// have exited.
+    ///
+    /// A loop thread cannot wait for itself, so this returns immediately
+    /// when called from one of the server's event loops.
+    pub fn join(&self) {
+        if self.on_loop_thread() { return; }
+
+        // The lock is held while joining so that a concurrent call to
+        // shutdown does not return before the threads have exited.
+        let mut threads = self.threads.lock();
+        let handles = mem::take(&mut *threads);
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Server {
+    fn on_loop_thread(&self) -> bool {
+        self.thread_ids.contains(&thread::current().id())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.shutdown().map_err(|e| {
+            warn!("{} during server shutdown", e);
+        });
+    }
+}
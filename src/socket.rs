@@ -5,35 +5,22 @@
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
 
 
-use std::collections::BTreeMap;
+use std::cmp;
 use std::io::{self, Error, ErrorKind};
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::os::unix::io::RawFd;
-use std::sync::Arc;
-use std::usize;
 
 use libc;
-use parking_lot::Mutex;
 
 use buf::Buffer;
-use event_loop;
+use zerocopy::ZeroCopy;
 
 
-type BufferMap = Mutex<BTreeMap<RawFd, Arc<Buffer>>>;
+/// Most slices handed to a single writev, well below IOV_MAX.
+const MAX_IOVECS: usize = 64;
 
 
-lazy_static! {
-    static ref RX_BUF_MAP: BufferMap = Mutex::new(BTreeMap::new());
-    static ref TX_BUF_MAP: BufferMap = Mutex::new(BTreeMap::new());
-}
-
-
-/// Initializes this socket's rx/tx buffers
-pub fn init(fd: RawFd) {
-    map_add(&RX_BUF_MAP, fd);
-    map_add(&TX_BUF_MAP, fd);
-}
-
 /// Clears the errno for this specific socket, and returns the errno
 /// if an error was present.
 pub fn get_last_error(fd: RawFd) -> Option<io::Error> {
@@ -57,26 +44,43 @@ pub fn get_last_error(fd: RawFd) -> Option<io::Error> {
     if errno == 0 { None } else { Some(Error::from_raw_os_error(errno)) }
 }
 
-/// Reads all available data until EAGAIN/EWOULDBLOCK is received, copying
-/// all data from kernel space into userspace.
-pub fn recv(fd: RawFd) -> io::Result<usize> {
-    let maybe_buf = map_get(&RX_BUF_MAP, fd);
-    if maybe_buf.is_none() {
-        let err = Error::new(ErrorKind::InvalidInput, "Unable to find fd");
-        return Err(err);
-    }
+/// Accepts a pending connection on the listening socket `fd`. The new
+/// socket is created nonblocking and close-on-exec.
+pub fn accept(fd: RawFd) -> io::Result<(RawFd, SocketAddr)> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let r = unsafe {
+        libc::accept4(fd,
+                      &mut storage as *mut _ as *mut libc::sockaddr,
+                      &mut len as *mut libc::socklen_t,
+                      libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC)
+    };
 
-    let rx_buf = maybe_buf.unwrap();
+    if r == -1 { return Err(Error::last_os_error()); }
 
+    match sockaddr_to_addr(&storage) {
+        Ok(addr) => Ok((r, addr)),
+        Err(err) => {
+            let _ = close(r);
+            Err(err)
+        }
+    }
+}
+
+/// Reads all available data until EAGAIN/EWOULDBLOCK is received, or
+/// `limit` bytes have been read, copying all data from kernel space into
+/// userspace.
+pub fn recv(fd: RawFd, rx_buf: &Buffer, limit: usize) -> io::Result<usize> {
     const BUF_LEN: usize = 4096;
-    let mut buf: [u8; BUF_LEN] = unsafe { mem::uninitialized() };
+    let mut buf = [0u8; BUF_LEN];
     let b = buf.as_mut_ptr() as *mut libc::c_void;
 
     // When the fds are set as EPOLLET mode, we need to read until
     // we receive EAGAIN/EWOULDBLOCK
     let mut total_recvd: usize = 0;
-    loop {
-        let r = unsafe { libc::recv(fd, b, BUF_LEN, 0) };
+    while total_recvd < limit {
+        let len = cmp::min(BUF_LEN, limit - total_recvd);
+        let r = unsafe { libc::recv(fd, b, len, 0) };
 
         if r == -1 {
             let err = Error::last_os_error();
@@ -95,100 +99,151 @@ pub fn recv(fd: RawFd) -> io::Result<usize> {
     Ok(total_recvd)
 }
 
-/// Sends all available data in current userspace buffer.
-pub fn send(fd: RawFd) -> io::Result<(usize, bool)> {
-    let maybe_buf = map_get(&TX_BUF_MAP, fd);
-    if maybe_buf.is_none() {
-        let err = Error::new(ErrorKind::InvalidInput, "Unable to find fd");
-        return Err(err);
-    }
-
-    let sock_buf = maybe_buf.unwrap();
-    let buf = sock_buf.extract(usize::MAX);
-    let l = buf.len();
-    let b = buf.as_ptr() as *const libc::c_void;
-    let r = unsafe { libc::send(fd, b, l, 0) };
+/// Reads and throws away all available data until EAGAIN/EWOULDBLOCK is
+/// received.
+pub fn discard(fd: RawFd) -> io::Result<usize> {
+    const BUF_LEN: usize = 4096;
+    let mut buf = [0u8; BUF_LEN];
+    let b = buf.as_mut_ptr() as *mut libc::c_void;
 
-    // Depending the amount sent/errno code, the caller of this
-    // function needs to know if they need to re-arm the fd in epoll
-    // with only read OR read and write flags.
+    let mut total_discarded: usize = 0;
+    loop {
+        let r = unsafe { libc::recv(fd, b, BUF_LEN, 0) };
 
-    // The only error we care to transform is EAGAIN/EWOULDBLOCK.
-    if r == -1 {
-        let e = Error::last_os_error();
-        if e.kind() == ErrorKind::WouldBlock {
-            return Ok((0, true));
+        if r == -1 {
+            let err = Error::last_os_error();
+            if err.kind() == ErrorKind::WouldBlock { break; }
+            return Err(err);
+        } else if r == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "EOF"));
+        } else {
+            total_discarded += r as usize;
         }
-        return Err(e);
     }
 
-    // People are dumb, no way to guarantee someone will not call our
-    // write pipeline with an empty buffer.
-    if r == 0 {
-        if buf.len() == 0 { return Ok((0, false)); }
-        // This should never happen, but it would do good to check
-        // for it anyway.
-        return Err(Error::new(ErrorKind::WriteZero, "WriteZero"));
-    }
+    Ok(total_discarded)
+}
 
-    let sent = r as usize;
-    let mut rearm_rw = false;
+/// Sends all available data in current userspace buffer until
+/// EAGAIN/EWOULDBLOCK is received, writing straight from the buffer's
+/// segments and removing whatever was sent. File regions are written with
+/// sendfile, in order with the bytes around them. With `zerocopy`, large
+/// enough shared segments are sent with `MSG_ZEROCOPY`.
+///
+/// Returns the number of bytes sent, and whether anything is left in the
+/// buffer.
+pub fn send(fd: RawFd,
+            sock_buf: &Buffer,
+            mut zerocopy: Option<&mut ZeroCopy>) -> io::Result<(usize, bool)>
+{
+    let mut total_sent: usize = 0;
+    loop {
+        let file = sock_buf.with_front_file(|in_fd, offset, len| {
+            let mut offset = offset as libc::off_t;
+            (unsafe { libc::sendfile(fd, in_fd, &mut offset, len) }, len)
+        });
+
+        let shared = match zerocopy {
+            Some(ref zc) if file.is_none() => {
+                zc.min_len().and_then(|min_len| sock_buf.front_shared(min_len))
+            }
+            _ => None
+        };
+
+        let (r, l) = match (file, shared, zerocopy.as_mut()) {
+            (Some(r), _, _) => r,
+            (None, Some((buf, pos)), Some(zc)) => {
+                let r = zc.send(fd, &buf, pos);
+
+                // Past the socket's optmem limit the kernel refuses to pin
+                // any more pages, the rest of this write is copied instead.
+                if r == -1 && Error::last_os_error().raw_os_error() == Some(libc::ENOBUFS) {
+                    zerocopy = None;
+                    continue;
+                }
+                (r, buf.len() - pos)
+            }
+            _ => sock_buf.with_io_slices(MAX_IOVECS, |slices| {
+                // IoSlice is guaranteed to be ABI compatible with iovec.
+                let iov = slices.as_ptr() as *const libc::iovec;
+                let l: usize = slices.iter().map(|s| s.len()).sum();
+                (unsafe { libc::writev(fd, iov, slices.len() as libc::c_int) }, l)
+            })
+        };
+
+        // People are dumb, no way to guarantee someone will not call our
+        // write pipeline with an empty buffer.
+        if l == 0 { return Ok((total_sent, false)); }
+
+        // The only error we care to transform is EAGAIN/EWOULDBLOCK,
+        // whatever is unsent stays where it is for the next write event.
+        if r == -1 {
+            let e = Error::last_os_error();
+            if e.kind() == ErrorKind::WouldBlock {
+                return Ok((total_sent, true));
+            }
+            if e.kind() == ErrorKind::Interrupted { continue; }
+            return Err(e);
+        }
+
+        // This should never happen, short of a file being truncated after
+        // its region was queued, but it would do good to check for it anyway.
+        if r == 0 {
+            return Err(Error::new(ErrorKind::WriteZero, "WriteZero"));
+        }
 
-    if sent < buf.len() {
-        // If we sent less than we tried to, this is a result of the
-        // internel socket's buffer being full, and we need to push
-        // what we didn't send back to the front of the buffer.
-        let unsent_buf = &buf[sent..buf.len()];
-        sock_buf.prepend(unsent_buf);
-        rearm_rw = true;
+        let sent = r as usize;
+        sock_buf.consume(sent);
+        total_sent += sent;
     }
+}
 
-    Ok((sent, rearm_rw))
+/// Sets or clears `TCP_CORK`, while set the kernel holds back partial
+/// packets until there is enough data queued to fill one.
+pub fn set_tcp_cork(fd: RawFd, cork: bool) -> io::Result<()> {
+    let v: libc::c_int = if cork { 1 } else { 0 };
+    let r = unsafe {
+        libc::setsockopt(fd,
+                         libc::IPPROTO_TCP,
+                         libc::TCP_CORK,
+                         &v as *const _ as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
 }
 
 pub fn shutdown(fd: RawFd) -> io::Result<()> {
-    map_del(&RX_BUF_MAP, fd);
-    map_del(&TX_BUF_MAP, fd);
-
     let r = unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
     if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
 }
 
-pub fn add_to_tx_buf(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
-    let maybe_buf = map_get(&TX_BUF_MAP, fd);
-    if maybe_buf.is_none() {
-        let err = Error::new(ErrorKind::InvalidInput, "Unable to find fd");
-        return Err(err);
-    }
-
-    let sock_buf = maybe_buf.unwrap();
-    sock_buf.append(buf);
-    let _ = try!(event_loop::needs_write(fd));
-
-    Ok(buf.len())
+/// Shuts down the transmit side of the socket, sending the peer EOF once
+/// everything already written has been delivered.
+pub fn shutdown_write(fd: RawFd) -> io::Result<()> {
+    let r = unsafe { libc::shutdown(fd, libc::SHUT_WR) };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
 }
 
-pub fn peek(fd: RawFd) -> io::Result<usize> {
-    match map_get(&RX_BUF_MAP, fd) {
-        Some(sock_buf) => Ok(sock_buf.len()),
-        None => Err(Error::new(ErrorKind::InvalidInput, "Unable to find fd"))
-    }
+/// Creates a nonblocking eventfd.
+pub fn eventfd() -> io::Result<RawFd> {
+    let r = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(r) }
 }
 
-pub fn take(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
-    match map_get(&RX_BUF_MAP, fd) {
-        Some(sock_buf) => {
-            let v = sock_buf.extract(buf.len());
-
-            let len = if buf.len() <= v.len() { buf.len() } else { v.len() };
-            for x in 0..len {
-                buf[x] = unsafe { *v.get_unchecked(x) };
-            }
+/// Increments the counter of the passed eventfd, waking anything waiting
+/// on it.
+pub fn eventfd_notify(fd: RawFd) -> io::Result<()> {
+    let v: u64 = 1;
+    let b = &v as *const u64 as *const libc::c_void;
+    let r = unsafe { libc::write(fd, b, mem::size_of::<u64>()) };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
+}
 
-            Ok(len)
-        },
-        None => Err(Error::new(ErrorKind::InvalidInput, "Unable to find fd"))
-    }
+/// Resets the counter of the passed eventfd.
+pub fn eventfd_reset(fd: RawFd) {
+    let mut v: u64 = 0;
+    let b = &mut v as *mut u64 as *mut libc::c_void;
+    unsafe { libc::read(fd, b, mem::size_of::<u64>()); }
 }
 
 pub fn close(fd: RawFd) -> io::Result<()> {
@@ -196,20 +251,32 @@ pub fn close(fd: RawFd) -> io::Result<()> {
     if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
 }
 
-fn map_add(m: &BufferMap, fd: RawFd) {
-    let mut map = (*m).lock();
-    map.insert(fd, Arc::new(Buffer::new()));
-}
-
-fn map_del(m: &BufferMap, fd: RawFd) {
-    let mut map = (*m).lock();
-    map.remove(&fd);
-}
-
-fn map_get(m: &BufferMap, fd: RawFd) -> Option<Arc<Buffer>> {
-    let map = (*m).lock();
-    match map.get(&fd) {
-        Some(s) => Some((*s).clone()),
-        None => None
+fn sockaddr_to_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let a = unsafe {
+                *(storage as *const _ as *const libc::sockaddr_in)
+            };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            let port = u16::from_be(a.sin_port);
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        libc::AF_INET6 => {
+            let a = unsafe {
+                *(storage as *const _ as *const libc::sockaddr_in6)
+            };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            let port = u16::from_be(a.sin6_port);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                port,
+                                                a.sin6_flowinfo,
+                                                a.sin6_scope_id)))
+        }
+        // Unix-domain peers are almost always unnamed, and a path could not
+        // be expressed as a SocketAddr anyway.
+        libc::AF_UNIX => {
+            Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)))
+        }
+        _ => Err(Error::new(ErrorKind::InvalidInput, "Unsupported address family"))
     }
 }
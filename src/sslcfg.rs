@@ -3,3 +3,433 @@
 // This Source Code Form is subject to the terms of the
 // Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
 // with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    AlpnError,
+    NameType,
+    SniError,
+    SslAlert,
+    SslContext,
+    SslContextBuilder,
+    SslFiletype,
+    SslMethod,
+    SslMode,
+    SslOptions,
+    SslRef,
+    SslVerifyMode,
+    SslVersion
+};
+use openssl::x509::X509Name;
+
+
+/// Settings for terminating TLS on a server's listener.
+///
+/// A certificate chain and private key are required, everything else
+/// defaults to OpenSSL's defaults. Further certificates can be served to
+/// clients asking for a particular host name, see `server_name`.
+#[derive(Debug, Clone, Default)]
+pub struct SslConfig {
+    certificate_chain: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+    /// Host names, with the certificate chain and private key served for
+    /// each, in the order they were added.
+    server_names: Vec<(String, PathBuf, PathBuf)>,
+    ciphers: Option<String>,
+    ciphersuites: Option<String>,
+    min_version: Option<TlsVersion>,
+    client_ca: Option<PathBuf>,
+    verify_client: VerifyClient,
+    /// Application protocols offered with ALPN, most preferred first.
+    alpn_protocols: Vec<String>
+}
+
+/// A TLS protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls1_0,
+    Tls1_1,
+    Tls1_2,
+    Tls1_3
+}
+
+/// Whether clients are asked for a certificate during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyClient {
+    /// No certificate is requested.
+    #[default]
+    None,
+    /// A certificate is requested, and the handshake fails if the one
+    /// presented does not verify, but clients may present none.
+    Optional,
+    /// A certificate is requested, and the handshake fails unless one is
+    /// presented and verifies.
+    Required
+}
+
+impl SslConfig {
+    /// Creates a new, empty SslConfig.
+    pub fn new() -> SslConfig { SslConfig::default() }
+
+    /// Sets the PEM file holding the server's certificate, followed by any
+    /// intermediate certificates up to, but not including, the root.
+    pub fn certificate_chain_file<P: AsRef<Path>>(mut self, path: P) -> SslConfig {
+        self.certificate_chain = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the PEM file holding the private key of the server's
+    /// certificate.
+    pub fn private_key_file<P: AsRef<Path>>(mut self, path: P) -> SslConfig {
+        self.private_key = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Serves the certificate chain and private key in the PEM files
+    /// `chain` and `key` to clients asking for the host `name` through
+    /// server name indication, instead of the default ones set with
+    /// `certificate_chain_file` and `private_key_file`. Clients asking for
+    /// any other name, or none, are served the default ones.
+    ///
+    /// Names match regardless of case. A name whose leftmost label is `*`,
+    /// such as `*.example.com`, matches any single label in its place, so
+    /// `www.example.com` but neither `example.com` nor `a.b.example.com`.
+    /// An exact name takes precedence over a wildcard matching it. Every
+    /// other setting applies to all names alike.
+    pub fn server_name<P, Q>(mut self, name: &str, chain: P, key: Q) -> SslConfig
+        where P: AsRef<Path>, Q: AsRef<Path>
+    {
+        self.server_names.push((name.to_lowercase(),
+                                chain.as_ref().to_path_buf(),
+                                key.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Sets the ciphers offered for TLS 1.2 and earlier, in OpenSSL's cipher
+    /// list format.
+    pub fn ciphers(mut self, ciphers: &str) -> SslConfig {
+        self.ciphers = Some(ciphers.to_owned());
+        self
+    }
+
+    /// Sets the cipher suites offered for TLS 1.3, in OpenSSL's cipher
+    /// suites format.
+    pub fn ciphersuites(mut self, ciphersuites: &str) -> SslConfig {
+        self.ciphersuites = Some(ciphersuites.to_owned());
+        self
+    }
+
+    /// Sets the oldest protocol version a client may negotiate.
+    pub fn min_protocol_version(mut self, version: TlsVersion) -> SslConfig {
+        self.min_version = Some(version);
+        self
+    }
+
+    /// Sets the application protocols offered to clients with ALPN, such as
+    /// `"h2"` and `"http/1.1"`, most preferred first. The first of them the
+    /// client also offers is selected, and the handshake goes on without
+    /// one if it offers none of them or does not use ALPN.
+    ///
+    /// The protocol selected is available from `Handler::on_connect`
+    /// onward, see `Connection::alpn_protocol` and
+    /// `Builder::protocol_handler`.
+    pub fn alpn_protocols(mut self, protocols: &[&str]) -> SslConfig {
+        self.alpn_protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Sets the PEM file holding the certificates of the CAs client
+    /// certificates are verified against. Their names are also sent to
+    /// clients, so they can pick which certificate to present.
+    pub fn client_ca_file<P: AsRef<Path>>(mut self, path: P) -> SslConfig {
+        self.client_ca = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets whether clients are asked for a certificate, anything other than
+    /// `VerifyClient::None` requires `client_ca_file`. Defaults to
+    /// `VerifyClient::None`.
+    ///
+    /// The certificate a client presented is available from
+    /// `Handler::on_connect` onward, see `Connection::peer_certificate`.
+    pub fn verify_client(mut self, verify: VerifyClient) -> SslConfig {
+        self.verify_client = verify;
+        self
+    }
+
+    /// Builds the OpenSSL context connections are accepted with, failing
+    /// with `InvalidInput` if the certificate or key are missing or do not
+    /// match, a server name or ALPN protocol is malformed, or clients are
+    /// verified without a CA file.
+    pub(crate) fn build(&self) -> io::Result<SslContext> {
+        let (chain, key) = match (&self.certificate_chain, &self.private_key) {
+            (Some(chain), Some(key)) => (chain, key),
+            _ => {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "Certificate chain and private key are required"));
+            }
+        };
+
+        let mut ctx = self.context(chain, key)?;
+        if !self.server_names.is_empty() {
+            let mut map = ServerNames::default();
+            for (name, chain, key) in self.server_names.iter() {
+                let host_ctx = self.context(chain, key)?.build();
+                map.insert(name, host_ctx)?;
+            }
+            ctx.set_servername_callback(move |ssl, alert| map.select(ssl, alert));
+        }
+
+        Ok(ctx.build())
+    }
+
+    /// Creates a context serving the certificate chain and private key in
+    /// `chain` and `key`, with every other setting applied.
+    fn context(&self, chain: &Path, key: &Path) -> io::Result<SslContextBuilder> {
+        let mut ctx = SslContextBuilder::new(SslMethod::tls()).map_err(ssl_error)?;
+
+        // Writes are retried from wherever the transmit buffer has moved to
+        // since, and complete as soon as any of it has been sent.
+        ctx.set_mode(SslMode::ACCEPT_MOVING_WRITE_BUFFER | SslMode::ENABLE_PARTIAL_WRITE);
+
+        // Once the handshake is done, connections are armed for reads and
+        // writes by what the handler does with them. A renegotiation could
+        // leave a read waiting for the socket to become writable, or a
+        // write for it to become readable, which would go unnoticed.
+        ctx.set_options(SslOptions::NO_RENEGOTIATION);
+
+        ctx.set_certificate_chain_file(chain).map_err(ssl_error)?;
+        ctx.set_private_key_file(key, SslFiletype::PEM).map_err(ssl_error)?;
+        ctx.check_private_key().map_err(ssl_error)?;
+
+        if let Some(ref ciphers) = self.ciphers {
+            ctx.set_cipher_list(ciphers).map_err(ssl_error)?;
+        }
+        if let Some(ref ciphersuites) = self.ciphersuites {
+            ctx.set_ciphersuites(ciphersuites).map_err(ssl_error)?;
+        }
+        if let Some(version) = self.min_version {
+            let version = match version {
+                TlsVersion::Tls1_0 => SslVersion::TLS1,
+                TlsVersion::Tls1_1 => SslVersion::TLS1_1,
+                TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+                TlsVersion::Tls1_3 => SslVersion::TLS1_3
+            };
+            ctx.set_min_proto_version(Some(version)).map_err(ssl_error)?;
+        }
+        self.configure_verify(&mut ctx)?;
+        self.configure_alpn(&mut ctx)?;
+
+        Ok(ctx)
+    }
+
+    /// Returns true if `protocol` is offered to clients with ALPN.
+    pub(crate) fn offers_protocol(&self, protocol: &str) -> bool {
+        self.alpn_protocols.iter().any(|p| p == protocol)
+    }
+
+    fn configure_verify(&self, ctx: &mut SslContextBuilder) -> io::Result<()> {
+        let mode = match self.verify_client {
+            VerifyClient::None => return Ok(()),
+            VerifyClient::Optional => SslVerifyMode::PEER,
+            VerifyClient::Required => {
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+            }
+        };
+
+        let ca = match self.client_ca {
+            Some(ref ca) => ca,
+            None => {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "Client CA file is required to verify clients"));
+            }
+        };
+        ctx.set_ca_file(ca).map_err(ssl_error)?;
+        ctx.set_client_ca_list(X509Name::load_client_ca_file(ca).map_err(ssl_error)?);
+        ctx.set_verify(mode);
+
+        // OpenSSL refuses to resume sessions of verified clients without one.
+        ctx.set_session_id_context(b"alnio").map_err(ssl_error)
+    }
+
+    fn configure_alpn(&self, ctx: &mut SslContextBuilder) -> io::Result<()> {
+        if self.alpn_protocols.is_empty() { return Ok(()); }
+
+        let mut protocols = Vec::with_capacity(self.alpn_protocols.len());
+        for p in self.alpn_protocols.iter() {
+            if p.is_empty() || p.len() > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, "Malformed ALPN protocol"));
+            }
+            protocols.push(p.as_bytes().to_vec());
+        }
+
+        ctx.set_alpn_select_callback(move |_, client| {
+            select_protocol(&protocols, client).ok_or(AlpnError::NOACK)
+        });
+        Ok(())
+    }
+}
+
+/// Contexts to switch a connection to by the host name its client asked
+/// for.
+#[derive(Default)]
+struct ServerNames {
+    exact: HashMap<String, SslContext>,
+    /// Keyed by what follows the `*.` of the name.
+    wildcard: HashMap<String, SslContext>
+}
+
+impl ServerNames {
+    fn insert(&mut self, name: &str, ctx: SslContext) -> io::Result<()> {
+        let name = name.trim_end_matches('.');
+        let (map, name) = match name.strip_prefix("*.") {
+            Some(name) => (&mut self.wildcard, name),
+            None => (&mut self.exact, name)
+        };
+
+        if name.is_empty() || name.contains('*') {
+            return Err(Error::new(ErrorKind::InvalidInput, "Malformed server name"));
+        }
+        map.insert(name.to_owned(), ctx);
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<&SslContext> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        if let Some(ctx) = self.exact.get(&name) { return Some(ctx); }
+
+        match name.find('.') {
+            Some(pos) if pos > 0 => self.wildcard.get(&name[pos + 1..]),
+            _ => None
+        }
+    }
+
+    /// Switches `ssl` to the context of the name its client asked for,
+    /// leaving it on the default one if there is none.
+    fn select(&self, ssl: &mut SslRef, _alert: &mut SslAlert) -> Result<(), SniError> {
+        let ctx = match ssl.servername(NameType::HOST_NAME).and_then(|n| self.get(n)) {
+            Some(ctx) => ctx,
+            None => return Ok(())
+        };
+        ssl.set_ssl_context(ctx).map_err(|_| SniError::ALERT_FATAL)
+    }
+}
+
+/// Returns the first of the server's `protocols` the client also offers,
+/// out of the list it sent in ALPN wire format.
+fn select_protocol<'a>(protocols: &[Vec<u8>], client: &'a [u8]) -> Option<&'a [u8]> {
+    let mut offered = Vec::new();
+    let mut rest = client;
+    while let Some((&len, tail)) = rest.split_first() {
+        let len = len as usize;
+        if tail.len() < len { break; }
+        offered.push(&tail[..len]);
+        rest = &tail[len..];
+    }
+
+    protocols.iter().find_map(|p| offered.iter().find(|&&o| o == &p[..]).copied())
+}
+
+fn ssl_error(err: ErrorStack) -> Error {
+    Error::new(ErrorKind::InvalidInput, err)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use openssl::ssl::{SslContext, SslMethod};
+
+    use super::{select_protocol, ServerNames};
+
+
+    fn context() -> SslContext { SslContext::builder(SslMethod::tls()).unwrap().build() }
+
+    fn names(names: &[&str]) -> ServerNames {
+        let mut map = ServerNames::default();
+        for name in names { map.insert(name, context()).unwrap(); }
+        map
+    }
+
+    /// Returns which of `names`, as inserted, `name` is served the context
+    /// of.
+    fn lookup(map: &ServerNames, name: &str) -> Option<String> {
+        let ctx = map.get(name)?;
+        map.exact.iter().map(|(n, c)| (n.clone(), c))
+            .chain(map.wildcard.iter().map(|(n, c)| (format!("*.{}", n), c)))
+            .find(|&(_, c)| ptr::eq(&**c, &**ctx))
+            .map(|(n, _)| n)
+    }
+
+    #[test]
+    fn exact_takes_precedence_over_wildcard() {
+        let map = names(&["*.example.com", "www.example.com"]);
+        assert_eq!(lookup(&map, "www.example.com"), Some("www.example.com".to_owned()));
+        assert_eq!(lookup(&map, "api.example.com"), Some("*.example.com".to_owned()));
+    }
+
+    #[test]
+    fn wildcard_matches_single_label() {
+        let map = names(&["*.example.com"]);
+        assert_eq!(lookup(&map, "a.example.com"), Some("*.example.com".to_owned()));
+        assert_eq!(lookup(&map, "a.b.example.com"), None);
+        assert_eq!(lookup(&map, "example.com"), None);
+        assert_eq!(lookup(&map, ".example.com"), None);
+    }
+
+    #[test]
+    fn names_fold_case_and_trailing_dot() {
+        let map = names(&["www.example.com", "*.example.org"]);
+        assert_eq!(lookup(&map, "WWW.Example.COM"), Some("www.example.com".to_owned()));
+        assert_eq!(lookup(&map, "www.example.com."), Some("www.example.com".to_owned()));
+        assert_eq!(lookup(&map, "Mail.EXAMPLE.org"), Some("*.example.org".to_owned()));
+    }
+
+    #[test]
+    fn malformed_names_rejected() {
+        let mut map = ServerNames::default();
+        for name in &["", ".", "*.", "*.*.example.com", "a.*.example.com", "*a.example.com"] {
+            assert!(map.insert(name, context()).is_err(), "{}", name);
+        }
+    }
+
+    fn protocols(protocols: &[&str]) -> Vec<Vec<u8>> {
+        protocols.iter().map(|p| p.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn select_protocol_in_server_order() {
+        let client = b"\x08http/1.1\x02h2";
+        assert_eq!(select_protocol(&protocols(&["h2", "http/1.1"]), client),
+                   Some(&b"h2"[..]));
+        assert_eq!(select_protocol(&protocols(&["http/1.1", "h2"]), client),
+                   Some(&b"http/1.1"[..]));
+        assert_eq!(select_protocol(&protocols(&["spdy/3", "h2"]), client),
+                   Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn select_protocol_without_overlap() {
+        assert_eq!(select_protocol(&protocols(&["h2"]), b"\x08http/1.1"), None);
+        assert_eq!(select_protocol(&protocols(&["h2"]), b""), None);
+        // Prefixes of an offered protocol do not match it.
+        assert_eq!(select_protocol(&protocols(&["h"]), b"\x02h2"), None);
+    }
+
+    #[test]
+    fn select_protocol_malformed_client_list() {
+        let server = protocols(&["http/1.1", "h2"]);
+        // Protocols before a length that runs past the end still count.
+        assert_eq!(select_protocol(&server, b"\x02h2\x09http/1.1"), Some(&b"h2"[..]));
+        assert_eq!(select_protocol(&server, b"\x09http/1.1"), None);
+        assert_eq!(select_protocol(&server, b"\x02h"), None);
+        // An empty protocol is skipped over.
+        assert_eq!(select_protocol(&server, b"\x00\x02h2"), Some(&b"h2"[..]));
+    }
+}
@@ -0,0 +1,302 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::collections::HashMap;
+use std::io::{self, Error};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use libc;
+
+
+/// Identifies a timer scheduled on an event loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+
+/// A hashed timing wheel.
+///
+/// Time is divided into ticks of a fixed length, and every timer lives in
+/// the slot its deadline tick hashes to. Timers further out than one
+/// revolution of the wheel share a slot with nearer ones and are skipped
+/// until their tick comes around. Inserting and cancelling are O(1),
+/// expiring is O(timers in the slots passed over).
+pub struct Wheel<T> {
+    start: Instant,
+    tick: Duration,
+    /// Number of ticks that have been processed.
+    current: u64,
+    slots: Vec<Vec<Entry<T>>>,
+    /// Slot each pending timer lives in.
+    index: HashMap<TimerId, usize>,
+    next_id: u64
+}
+
+struct Entry<T> {
+    id: TimerId,
+    at: u64,
+    value: T
+}
+
+impl<T> Wheel<T> {
+    /// Creates a new Wheel with `num_slots` slots, each `tick` long.
+    pub fn new(tick: Duration, num_slots: usize) -> Wheel<T> {
+        let mut slots = Vec::with_capacity(num_slots);
+        for _ in 0..num_slots { slots.push(Vec::new()); }
+
+        Wheel {
+            start: Instant::now(),
+            tick,
+            current: 0,
+            slots,
+            index: HashMap::new(),
+            next_id: 0
+        }
+    }
+
+    /// Schedules `value` to be returned by `expire` once `deadline` has
+    /// passed. Deadlines are rounded up to the next tick.
+    pub fn insert(&mut self, deadline: Instant, value: T) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        self.reinsert(id, deadline, value);
+        id
+    }
+
+    /// Schedules `value` under an id previously returned by `insert`
+    /// whose timer has since expired, so repeating timers keep their id.
+    pub fn reinsert(&mut self, id: TimerId, deadline: Instant, value: T) {
+        let at = self.tick_at(deadline);
+        let at = if at <= self.current { self.current + 1 } else { at };
+
+        let slot = self.slot(at);
+        self.slots[slot].push(Entry { id, at, value });
+        self.index.insert(id, slot);
+    }
+
+    /// Cancels the timer, returning its value if it was still pending.
+    pub fn remove(&mut self, id: TimerId) -> Option<T> {
+        let slot = self.index.remove(&id)?;
+        let entries = &mut self.slots[slot];
+        entries.iter().position(|e| e.id == id).map(|pos| entries.swap_remove(pos).value)
+    }
+
+    /// Advances the wheel up to `now`, returning every timer whose
+    /// deadline has passed.
+    pub fn expire(&mut self, now: Instant) -> Vec<(TimerId, T)> {
+        let mut expired = Vec::new();
+        let target = self.tick_at_floor(now);
+        while self.current < target && !self.index.is_empty() {
+            self.current += 1;
+
+            let current = self.current;
+            let slot = self.slot(current);
+            let entries = mem::take(&mut self.slots[slot]);
+            for entry in entries {
+                if entry.at <= current {
+                    self.index.remove(&entry.id);
+                    expired.push((entry.id, entry.value));
+                } else {
+                    self.slots[slot].push(entry);
+                }
+            }
+        }
+
+        // Nothing is pending, jump straight to the present rather than
+        // walking every empty slot in between.
+        if self.current < target { self.current = target; }
+
+        expired
+    }
+
+    /// Returns the instant the earliest pending timer expires at.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        if self.index.is_empty() { return None; }
+
+        // Most of the time the nearest timer is within one revolution
+        // of the wheel, only look at every entry if it is not.
+        let num_slots = self.slots.len() as u64;
+        let mut at = None;
+        for x in 1..(num_slots + 1) {
+            let tick = self.current + x;
+            if self.slots[self.slot(tick)].iter().any(|e| e.at == tick) {
+                at = Some(tick);
+                break;
+            }
+        }
+
+        let at = match at {
+            Some(at) => at,
+            None => {
+                self.slots.iter()
+                    .flat_map(|entries| entries.iter().map(|e| e.at))
+                    .min()
+                    .unwrap()
+            }
+        };
+
+        Some(self.start + nanos_duration(at * duration_nanos(self.tick)))
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+
+    /// Returns the first tick at or after `instant`.
+    fn tick_at(&self, instant: Instant) -> u64 {
+        let nanos = duration_nanos(self.since_start(instant));
+        let tick = duration_nanos(self.tick);
+        nanos.div_ceil(tick)
+    }
+
+    /// Returns the last tick at or before `instant`.
+    fn tick_at_floor(&self, instant: Instant) -> u64 {
+        duration_nanos(self.since_start(instant)) / duration_nanos(self.tick)
+    }
+
+    fn since_start(&self, instant: Instant) -> Duration {
+        if instant <= self.start {
+            Duration::new(0, 0)
+        } else {
+            instant - self.start
+        }
+    }
+}
+
+/// Creates a nonblocking timerfd on the monotonic clock.
+pub fn timerfd() -> io::Result<RawFd> {
+    let flags = libc::TFD_NONBLOCK | libc::TFD_CLOEXEC;
+    let r = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, flags) };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(r) }
+}
+
+/// Arms the timerfd to fire once at `deadline`, or disarms it if `None`.
+pub fn timerfd_arm(fd: RawFd, deadline: Option<Instant>) -> io::Result<()> {
+    let mut spec: libc::itimerspec = unsafe { mem::zeroed() };
+    if let Some(deadline) = deadline {
+        let now = Instant::now();
+        let d = if deadline > now { deadline - now } else { Duration::new(0, 0) };
+
+        // An all zero it_value disarms the timer, which is not what
+        // a deadline that has already passed wants.
+        spec.it_value.tv_sec = d.as_secs() as libc::time_t;
+        spec.it_value.tv_nsec = d.subsec_nanos() as libc::c_long;
+        if d.as_secs() == 0 && d.subsec_nanos() == 0 {
+            spec.it_value.tv_nsec = 1;
+        }
+    }
+
+    let r = unsafe { libc::timerfd_settime(fd, 0, &spec, ptr::null_mut()) };
+    if r == -1 { Err(Error::last_os_error()) } else { Ok(()) }
+}
+
+/// Clears the expiration count of the timerfd.
+pub fn timerfd_reset(fd: RawFd) {
+    let mut v: u64 = 0;
+    let b = &mut v as *mut u64 as *mut libc::c_void;
+    unsafe { libc::read(fd, b, mem::size_of::<u64>()); }
+}
+
+fn duration_nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+fn nanos_duration(nanos: u64) -> Duration {
+    Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{TimerId, Wheel};
+
+
+    const SLOTS: usize = 8;
+
+    fn wheel() -> Wheel<&'static str> { Wheel::new(Duration::from_millis(10), SLOTS) }
+
+    /// Returns the instant `ticks` ticks after `wheel` was created.
+    fn at<T>(wheel: &Wheel<T>, ticks: u64) -> Instant {
+        wheel.start + Duration::from_millis(10 * ticks)
+    }
+
+    fn values<T>(expired: Vec<(TimerId, T)>) -> Vec<T> {
+        expired.into_iter().map(|(_, value)| value).collect()
+    }
+
+    #[test]
+    fn slot_wrap_around() {
+        let mut w = wheel();
+        let keep = at(&w, 100);
+        w.insert(keep, "keep");
+        assert!(w.expire(at(&w, 6)).is_empty());
+
+        // Tick 10 hashes to slot 2, behind the current tick's slot.
+        let deadline = at(&w, 10);
+        w.insert(deadline, "wrapped");
+        assert_eq!(w.next_expiry(), Some(deadline));
+        assert!(w.expire(at(&w, 9)).is_empty());
+        assert_eq!(values(w.expire(at(&w, 10))), vec!["wrapped"]);
+        assert_eq!(w.next_expiry(), Some(keep));
+    }
+
+    #[test]
+    fn multi_round_timers() {
+        let mut w = wheel();
+        let rounds = SLOTS as u64;
+        w.insert(at(&w, 3 + 2 * rounds), "third");
+        w.insert(at(&w, 3 + rounds), "second");
+        w.insert(at(&w, 3), "first");
+
+        assert_eq!(values(w.expire(at(&w, 3))), vec!["first"]);
+        assert_eq!(w.next_expiry(), Some(at(&w, 3 + rounds)));
+        assert!(w.expire(at(&w, 2 + rounds)).is_empty());
+        assert_eq!(values(w.expire(at(&w, 3 + rounds))), vec!["second"]);
+        assert_eq!(values(w.expire(at(&w, 3 + 2 * rounds))), vec!["third"]);
+        assert_eq!(w.next_expiry(), None);
+    }
+
+    #[test]
+    fn next_expiry_beyond_one_revolution() {
+        let mut w = wheel();
+        let far = at(&w, 5 * SLOTS as u64 + 1);
+        w.insert(far, "far");
+        assert_eq!(w.next_expiry(), Some(far));
+        assert_eq!(values(w.expire(far)), vec!["far"]);
+    }
+
+    #[test]
+    fn remove_expired() {
+        let mut w = wheel();
+        let id = w.insert(at(&w, 2), "expired");
+        let other = w.insert(at(&w, 2 + SLOTS as u64), "other");
+
+        assert_eq!(values(w.expire(at(&w, 2))), vec!["expired"]);
+        assert_eq!(w.remove(id), None);
+
+        // The entry sharing its slot is unaffected.
+        assert_eq!(w.next_expiry(), Some(at(&w, 2 + SLOTS as u64)));
+        assert_eq!(w.remove(other), Some("other"));
+        assert_eq!(w.remove(other), None);
+        assert_eq!(w.next_expiry(), None);
+    }
+
+    #[test]
+    fn reinsert_keeps_id() {
+        let mut w = wheel();
+        let id = w.insert(at(&w, 1), "tick");
+        assert_eq!(w.expire(at(&w, 1)), vec![(id, "tick")]);
+
+        w.reinsert(id, at(&w, 2), "tock");
+        assert_eq!(w.expire(at(&w, 2)), vec![(id, "tock")]);
+    }
+}
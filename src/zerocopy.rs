@@ -0,0 +1,139 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+use std::collections::VecDeque;
+use std::io::{self, Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::Arc;
+
+use libc;
+
+
+/// libc has every other `SO_EE_ORIGIN_*` value, but not this one.
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+
+
+/// Payloads a socket has handed to the kernel with `MSG_ZEROCOPY`, kept
+/// alive until the kernel reports it is done with them.
+///
+/// The kernel numbers every successful zero-copy send on a socket, starting
+/// at 0, and reports completions on the socket's error queue as ranges of
+/// those numbers.
+pub struct ZeroCopy {
+    /// Smallest remaining length of a shared segment sent zero-copy, `None`
+    /// once disabled while earlier sends are still in flight.
+    min_len: Option<usize>,
+    /// Number the kernel gives the next zero-copy send.
+    next_seq: u32,
+    /// Payloads referenced by each send still in flight, oldest first.
+    in_flight: VecDeque<(u32, Arc<[u8]>)>
+}
+
+impl ZeroCopy {
+    /// Enables `SO_ZEROCOPY` on the socket `fd`, shared segments with at
+    /// least `min_len` bytes left are then sent zero-copy.
+    pub fn enable(fd: RawFd, min_len: usize) -> io::Result<ZeroCopy> {
+        let v: libc::c_int = 1;
+        let r = unsafe {
+            libc::setsockopt(fd,
+                             libc::SOL_SOCKET,
+                             libc::SO_ZEROCOPY,
+                             &v as *const _ as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if r == -1 { return Err(Error::last_os_error()); }
+
+        Ok(ZeroCopy {
+            min_len: Some(min_len),
+            next_seq: 0,
+            in_flight: VecDeque::new()
+        })
+    }
+
+    pub fn min_len(&self) -> Option<usize> { self.min_len }
+
+    pub fn set_min_len(&mut self, min_len: Option<usize>) { self.min_len = min_len; }
+
+    /// Returns the number of sends the kernel has not yet completed.
+    pub fn in_flight(&self) -> usize { self.in_flight.len() }
+
+    /// Sends as much of `buf`, from `pos` on, as the socket `fd` accepts with
+    /// `MSG_ZEROCOPY`, holding on to `buf` until the kernel completes the
+    /// send. Returns the result of the call to send.
+    pub fn send(&mut self, fd: RawFd, buf: &Arc<[u8]>, pos: usize) -> isize {
+        let b = buf[pos..].as_ptr() as *const libc::c_void;
+        let r = unsafe { libc::send(fd, b, buf.len() - pos, libc::MSG_ZEROCOPY) };
+        if r > 0 {
+            self.in_flight.push_back((self.next_seq, buf.clone()));
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        r
+    }
+
+    /// Reads every completion from the error queue of the socket `fd`,
+    /// releasing the payloads of completed sends. Returns the number of
+    /// sends completed.
+    pub fn reap(&mut self, fd: RawFd) -> io::Result<usize> {
+        let mut completed = 0;
+        loop {
+            let (lo, hi) = match recv_completion(fd)? {
+                Some(range) => range,
+                None => return Ok(completed)
+            };
+
+            // Ranges are inclusive, and wrap along with the numbering.
+            let before = self.in_flight.len();
+            self.in_flight.retain(|&(seq, _)| {
+                seq.wrapping_sub(lo) > hi.wrapping_sub(lo)
+            });
+            completed += before - self.in_flight.len();
+        }
+    }
+}
+
+/// Reads the next zero-copy completion from the error queue of the socket
+/// `fd`, returning `None` once the queue is empty.
+fn recv_completion(fd: RawFd) -> io::Result<Option<(u32, u32)>> {
+    let mut control = [0u8; 128];
+    loop {
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let r = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE) };
+        if r == -1 {
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::WouldBlock => return Ok(None),
+                ErrorKind::Interrupted => continue,
+                _ => return Err(err)
+            }
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            let recverr = (level == libc::SOL_IP && ty == libc::IP_RECVERR)
+                || (level == libc::SOL_IPV6 && ty == libc::IPV6_RECVERR);
+
+            if recverr {
+                let ee = unsafe {
+                    ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err)
+                };
+                if ee.ee_origin == SO_EE_ORIGIN_ZEROCOPY && ee.ee_errno == 0 {
+                    return Ok(Some((ee.ee_info, ee.ee_data)));
+                }
+            }
+
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+
+        // Anything else on the error queue is not ours to handle.
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+extern crate alnio;
+
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alnio::{Builder, Callbacks, Connection, Server, TxPolicy};
+
+
+const CHUNK_LEN: usize = 16 * 1024;
+/// Far more than loopback socket buffers hold.
+const FILL_LIMIT: usize = 64 << 20;
+
+
+/// Polls `f` until it returns true, failing the test after five seconds.
+fn wait_until<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Starts `builder` with `callbacks`, connects to it, and returns the
+/// server along with both ends of the connection.
+fn accept(builder: Builder, callbacks: Callbacks) -> (Server, TcpStream, Connection) {
+    let accepted: Arc<Mutex<Option<Connection>>> = Arc::new(Mutex::new(None));
+    let on_connect = accepted.clone();
+    let server = builder
+        .handler(callbacks.on_connect(move |conn| {
+            *on_connect.lock().unwrap() = Some(conn.clone());
+        }))
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let client = TcpStream::connect(server.local_addr()).unwrap();
+    wait_until(|| accepted.lock().unwrap().is_some());
+    let conn = accepted.lock().unwrap().take().unwrap();
+    (server, client, conn)
+}
+
+/// Sends chunks to `conn` until a send fails, returning the number of
+/// bytes sent before that along with the error.
+fn fill(conn: &Connection) -> (usize, Option<io::Error>) {
+    let chunk = [b'x'; CHUNK_LEN];
+    let mut sent = 0;
+    while sent < FILL_LIMIT {
+        match conn.send(&chunk) {
+            Ok(n) => sent += n,
+            Err(err) => return (sent, Some(err))
+        }
+    }
+    (sent, None)
+}
+
+/// Reads exactly `len` bytes from `client`.
+fn read_len(client: &mut TcpStream, len: usize) {
+    let mut buf = vec![0u8; len];
+    client.read_exact(&mut buf).unwrap();
+}
+
+#[test]
+fn rx_high_watermark_stops_reads() {
+    const HIGH_WATERMARK: usize = 1024;
+    let (_server, mut client, conn) = accept(
+        Builder::new().rx_high_watermark(HIGH_WATERMARK),
+        Callbacks::new());
+
+    client.write_all(&[b'x'; 64 * 1024]).unwrap();
+    wait_until(|| conn.bytes_avail().unwrap() == HIGH_WATERMARK);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(conn.bytes_avail().unwrap(), HIGH_WATERMARK);
+
+    // Each recv drops the buffer below the watermark, resuming reads.
+    let mut total = 0;
+    let mut buf = [0u8; 4096];
+    wait_until(|| {
+        total += conn.recv(&mut buf).unwrap();
+        assert!(conn.bytes_avail().unwrap() <= HIGH_WATERMARK);
+        total == 64 * 1024
+    });
+}
+
+#[test]
+fn pause_reading_leaves_data_in_the_socket() {
+    let received = Arc::new(AtomicUsize::new(0));
+    let on_recv = received.clone();
+    let (_server, mut client, conn) = accept(
+        Builder::new(),
+        Callbacks::new().on_recv(move |_| {
+            on_recv.fetch_add(1, Ordering::SeqCst);
+        }));
+
+    conn.pause_reading().unwrap();
+    assert!(conn.is_reading_paused());
+    client.write_all(b"hello").unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(received.load(Ordering::SeqCst), 0);
+    assert_eq!(conn.bytes_avail().unwrap(), 0);
+
+    conn.resume_reading().unwrap();
+    wait_until(|| received.load(Ordering::SeqCst) == 1);
+    assert_eq!(conn.bytes_avail().unwrap(), 5);
+}
+
+#[test]
+fn tx_policy_reject() {
+    let (_server, mut client, conn) = accept(
+        Builder::new().tx_high_watermark(64 * 1024).tx_policy(TxPolicy::Reject),
+        Callbacks::new());
+
+    let (sent, err) = fill(&conn);
+    assert_eq!(err.unwrap().kind(), ErrorKind::WouldBlock);
+
+    // Nothing rejected was queued, and the connection stays usable.
+    read_len(&mut client, sent);
+    wait_until(|| conn.tx_pending() == 0);
+    conn.send(b"end").unwrap();
+    let mut end = [0u8; 3];
+    client.read_exact(&mut end).unwrap();
+    assert_eq!(&end, b"end");
+}
+
+#[test]
+fn tx_policy_close() {
+    let closed = Arc::new(AtomicUsize::new(0));
+    let on_close = closed.clone();
+    let (_server, mut client, conn) = accept(
+        Builder::new().tx_high_watermark(64 * 1024).tx_policy(TxPolicy::Close),
+        Callbacks::new().on_close(move |_| {
+            on_close.fetch_add(1, Ordering::SeqCst);
+        }));
+
+    let (_, err) = fill(&conn);
+    assert_eq!(err.unwrap().kind(), ErrorKind::BrokenPipe);
+    wait_until(|| closed.load(Ordering::SeqCst) == 1);
+    assert_eq!(conn.send(b"x").unwrap_err().kind(), ErrorKind::BrokenPipe);
+
+    // The client sees the connection end, whatever it had been sent.
+    let mut buf = Vec::new();
+    let _ = client.read_to_end(&mut buf);
+}
+
+#[test]
+fn tx_policy_accept_reports_each_crossing_once() {
+    let high = Arc::new(AtomicUsize::new(0));
+    let drained = Arc::new(AtomicUsize::new(0));
+    let (on_high, on_drained) = (high.clone(), drained.clone());
+    let (_server, mut client, conn) = accept(
+        Builder::new()
+            .tx_high_watermark(64 * 1024)
+            .tx_low_watermark(16 * 1024)
+            .tx_policy(TxPolicy::Accept),
+        Callbacks::new()
+            .on_tx_high_watermark(move |_| {
+                on_high.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_tx_drained(move |_| {
+                on_drained.fetch_add(1, Ordering::SeqCst);
+            }));
+
+    for crossing in 1..3 {
+        // Everything is buffered, however far past the watermark it goes.
+        let (sent, err) = fill(&conn);
+        assert!(err.is_none());
+        assert!(conn.tx_pending() > 64 * 1024);
+        assert_eq!(high.load(Ordering::SeqCst), crossing);
+        assert_eq!(drained.load(Ordering::SeqCst), crossing - 1);
+
+        read_len(&mut client, sent);
+        wait_until(|| drained.load(Ordering::SeqCst) == crossing);
+        assert_eq!(high.load(Ordering::SeqCst), crossing);
+    }
+}
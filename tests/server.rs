@@ -0,0 +1,186 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+extern crate alnio;
+
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alnio::{Balance, Builder, Callbacks, Server};
+
+
+/// Polls `f` until it returns true, failing the test after five seconds.
+fn wait_until<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn least_loaded_spreads_a_burst() {
+    let server = Builder::new()
+        .handler(Callbacks::new())
+        .event_loops(4)
+        .balance(Balance::LeastLoaded)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let addr = server.local_addr();
+    let clients: Vec<TcpStream> = (0..64)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+
+    wait_until(|| {
+        server.stats().iter().map(|s| s.connections).sum::<usize>() == 64
+    });
+
+    // A connection being registered is briefly counted both as handed
+    // off and as registered, which may tip a single pick.
+    let counts: Vec<usize> = server.stats().iter().map(|s| s.connections).collect();
+    let max = *counts.iter().max().unwrap();
+    let min = *counts.iter().min().unwrap();
+    assert!(max - min <= 2, "uneven spread {:?}", counts);
+
+    drop(clients);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn shutdown_closes_connections_and_joins() {
+    let closed = Arc::new(AtomicUsize::new(0));
+    let on_close = closed.clone();
+    let server = Builder::new()
+        .handler(Callbacks::new().on_close(move |_| {
+            on_close.fetch_add(1, Ordering::SeqCst);
+        }))
+        .event_loops(2)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let _client = TcpStream::connect(server.local_addr()).unwrap();
+    wait_until(|| server.stats().iter().any(|s| s.connections == 1));
+
+    server.shutdown().unwrap();
+    server.join();
+    assert_eq!(closed.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn shutdown_from_a_handler() {
+    let slot: Arc<Mutex<Option<Arc<Server>>>> = Arc::new(Mutex::new(None));
+    let closed = Arc::new(AtomicUsize::new(0));
+
+    let on_recv = slot.clone();
+    let on_close = closed.clone();
+    let server = Arc::new(Builder::new()
+        .handler(Callbacks::new()
+            .on_recv(move |_| {
+                let server = on_recv.lock().unwrap().take().unwrap();
+                server.shutdown().unwrap();
+                server.join();
+            })
+            .on_close(move |_| {
+                on_close.fetch_add(1, Ordering::SeqCst);
+            }))
+        .event_loops(2)
+        .start("127.0.0.1:0")
+        .unwrap());
+    *slot.lock().unwrap() = Some(server.clone());
+
+    let mut client = TcpStream::connect(server.local_addr()).unwrap();
+    client.write_all(b"stop").unwrap();
+
+    wait_until(|| closed.load(Ordering::SeqCst) == 1);
+    server.join();
+    assert!(slot.lock().unwrap().is_none());
+}
+
+#[test]
+fn drain_under_connection_churn() {
+    // Too large to be sent at once, only a graceful close delivers it all.
+    const GREETING_LEN: usize = 1 << 20;
+
+    let connected = Arc::new(AtomicUsize::new(0));
+    let closed = Arc::new(AtomicUsize::new(0));
+    let (on_connect, on_close) = (connected.clone(), closed.clone());
+    let server = Builder::new()
+        .handler(Callbacks::new()
+            .on_connect(move |conn| {
+                on_connect.fetch_add(1, Ordering::SeqCst);
+                conn.send(&vec![b'x'; GREETING_LEN]).unwrap();
+            })
+            .on_error(|conn, _| {
+                let _ = conn.shutdown();
+            })
+            .on_close(move |_| {
+                on_close.fetch_add(1, Ordering::SeqCst);
+            }))
+        .event_loops(4)
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    // Each client reconnects until the listener is gone, counting the
+    // connections that received the whole greeting.
+    let addr = server.local_addr();
+    let greeted = Arc::new(AtomicUsize::new(0));
+    let clients: Vec<_> = (0..32).map(|_| {
+        let greeted = greeted.clone();
+        thread::spawn(move || {
+            while let Ok(mut stream) = TcpStream::connect(addr) {
+                let mut buf = vec![0; GREETING_LEN];
+                if stream.read_exact(&mut buf).is_ok() {
+                    greeted.fetch_add(1, Ordering::SeqCst);
+                }
+                let _ = stream.shutdown(Shutdown::Write);
+                let _ = stream.read_to_end(&mut buf);
+            }
+        })
+    }).collect();
+
+    wait_until(|| connected.load(Ordering::SeqCst) >= 32);
+    server.drain(Duration::from_secs(10)).unwrap();
+    for client in clients { client.join().unwrap(); }
+
+    let connected = connected.load(Ordering::SeqCst);
+    assert_eq!(closed.load(Ordering::SeqCst), connected);
+    assert_eq!(greeted.load(Ordering::SeqCst), connected);
+}
+
+#[test]
+fn servers_keep_their_own_handlers() {
+    let start = |greeting: &'static [u8], connected: Arc<AtomicUsize>| {
+        Builder::new()
+            .handler(Callbacks::new().on_connect(move |conn| {
+                connected.fetch_add(1, Ordering::SeqCst);
+                conn.send(greeting).unwrap();
+            }))
+            .start("127.0.0.1:0")
+            .unwrap()
+    };
+    let (connected_a, connected_b) = (Arc::new(AtomicUsize::new(0)),
+                                      Arc::new(AtomicUsize::new(0)));
+    let a = start(b"a", connected_a.clone());
+    let b = start(b"b", connected_b.clone());
+    assert!(a.local_addr() != b.local_addr());
+
+    for &(server, greeting) in &[(&a, b"a"), (&b, b"b"), (&a, b"a")] {
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        let mut buf = [0u8; 1];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, greeting);
+    }
+
+    assert_eq!(connected_a.load(Ordering::SeqCst), 2);
+    assert_eq!(connected_b.load(Ordering::SeqCst), 1);
+}
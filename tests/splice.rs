@@ -0,0 +1,136 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+extern crate alnio;
+
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alnio::{Builder, Callbacks, Connection, Server};
+
+
+/// Polls `f` until it returns true, failing the test after five seconds.
+fn wait_until<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Server whose connections are shutdown on EOF, keeping a handle to
+/// each of them and counting those closed.
+struct Relay {
+    server: Server,
+    accepted: Arc<Mutex<Vec<Connection>>>,
+    closed: Arc<AtomicUsize>
+}
+
+impl Relay {
+    fn start() -> Relay {
+        let accepted = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(AtomicUsize::new(0));
+        let (on_connect, on_close) = (accepted.clone(), closed.clone());
+        let server = Builder::new()
+            .handler(Callbacks::new()
+                .on_connect(move |conn| {
+                    on_connect.lock().unwrap().push(conn.clone());
+                })
+                .on_error(|conn, _| {
+                    let _ = conn.shutdown();
+                })
+                .on_close(move |_| {
+                    on_close.fetch_add(1, Ordering::SeqCst);
+                }))
+            .start("127.0.0.1:0")
+            .unwrap();
+
+        Relay { server, accepted, closed }
+    }
+
+    /// Connects a client, returning it along with the server's end.
+    fn connect(&self) -> (TcpStream, Connection) {
+        let n = self.accepted.lock().unwrap().len();
+        let client = TcpStream::connect(self.server.local_addr()).unwrap();
+        wait_until(|| self.accepted.lock().unwrap().len() > n);
+        let conn = self.accepted.lock().unwrap()[n].clone();
+        (client, conn)
+    }
+
+    fn wait_closed(&self, n: usize) {
+        wait_until(|| self.closed.load(Ordering::SeqCst) == n);
+    }
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[test]
+fn relay_under_backpressure() {
+    const LEN: usize = 32 << 20;
+    let relay = Relay::start();
+    let (mut source, source_conn) = relay.connect();
+    let (mut target, target_conn) = relay.connect();
+    source_conn.splice_to(&target_conn).unwrap();
+
+    // Far more than the pipe and both sockets hold, so the source is
+    // paused until the target starts reading.
+    let writer = thread::spawn(move || {
+        source.write_all(&pattern(LEN)).unwrap();
+        source
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    let mut received = vec![0u8; LEN];
+    target.read_exact(&mut received).unwrap();
+    assert!(received == pattern(LEN));
+    writer.join().unwrap();
+}
+
+#[test]
+fn source_closing_unsplices_both_ends() {
+    let relay = Relay::start();
+    let (source, source_conn) = relay.connect();
+    let (mut target, target_conn) = relay.connect();
+    source_conn.splice_to(&target_conn).unwrap();
+
+    drop(source);
+    relay.wait_closed(1);
+
+    // The target is free to be spliced to again.
+    let (mut other, other_conn) = relay.connect();
+    other_conn.splice_to(&target_conn).unwrap();
+    other.write_all(b"again").unwrap();
+    let mut buf = [0u8; 5];
+    target.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"again");
+}
+
+#[test]
+fn target_closing_unsplices_both_ends() {
+    let relay = Relay::start();
+    let (mut source, source_conn) = relay.connect();
+    let (target, target_conn) = relay.connect();
+    source_conn.splice_to(&target_conn).unwrap();
+
+    drop(target);
+    relay.wait_closed(1);
+
+    // The source is free to splice to another connection.
+    let (mut other, other_conn) = relay.connect();
+    source_conn.splice_to(&other_conn).unwrap();
+    source.write_all(b"again").unwrap();
+    let mut buf = [0u8; 5];
+    other.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"again");
+}
@@ -0,0 +1,46 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+extern crate alnio;
+
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use alnio::{Builder, Callbacks, TimerId};
+
+
+#[test]
+fn cancel_a_timer_due_in_the_same_tick() {
+    let server = Builder::new()
+        .handler(Callbacks::new())
+        .start("127.0.0.1:0")
+        .unwrap();
+    let handle = server.event_loops().remove(0);
+
+    // Each timeout cancels the other, so only whichever runs first may
+    // run at all, even though both leave the wheel together.
+    let ids: Arc<Mutex<Vec<TimerId>>> = Arc::new(Mutex::new(Vec::new()));
+    let ran: Arc<Mutex<Vec<(usize, bool)>>> = Arc::new(Mutex::new(Vec::new()));
+    {
+        let mut scheduled = ids.lock().unwrap();
+        for n in 0..2 {
+            let (h, ids, ran) = (handle.clone(), ids.clone(), ran.clone());
+            let id = handle.set_timeout(Duration::from_millis(50), move || {
+                let other = ids.lock().unwrap()[1 - n];
+                ran.lock().unwrap().push((n, h.cancel_timer(other)));
+            }).unwrap();
+            scheduled.push(id);
+        }
+    }
+
+    thread::sleep(Duration::from_millis(200));
+    let ran = ran.lock().unwrap();
+    assert_eq!(ran.len(), 1, "ran {:?}", *ran);
+    assert!(ran[0].1);
+}
@@ -0,0 +1,109 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+#![cfg(feature = "ssl")]
+
+
+extern crate alnio;
+extern crate openssl;
+
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process;
+
+use alnio::{Builder, Callbacks, SslConfig};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslConnector, SslMethod};
+use openssl::x509::{X509Builder, X509NameBuilder};
+use openssl::x509::extension::SubjectAlternativeName;
+
+
+/// Writes a freshly generated self-signed certificate for `localhost`,
+/// and its key, returning the paths of both.
+fn self_signed(name: &str) -> (PathBuf, PathBuf) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", "localhost").unwrap();
+    let subject = subject.build();
+
+    let mut cert = X509Builder::new().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let dir = env::temp_dir().join(format!("alnio-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    File::create(&cert_path).unwrap().write_all(&cert.to_pem().unwrap()).unwrap();
+    File::create(&key_path).unwrap()
+        .write_all(&key.private_key_to_pem_pkcs8().unwrap())
+        .unwrap();
+    (cert_path, key_path)
+}
+
+#[test]
+fn handshake_and_echo() {
+    let (cert, key) = self_signed("echo");
+    let server = Builder::new()
+        .handler(Callbacks::new()
+            .on_recv(|conn| {
+                let mut buf = vec![0; 64 * 1024];
+                while let Ok(n) = conn.recv(&mut buf) {
+                    if n == 0 { break; }
+                    conn.send(&buf[..n]).unwrap();
+                }
+            })
+            .on_error(|conn, _| {
+                let _ = conn.shutdown();
+            }))
+        .ssl(SslConfig::new().certificate_chain_file(&cert).private_key_file(&key))
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_ca_file(&cert).unwrap();
+    let connector = connector.build();
+    let tcp = TcpStream::connect(server.local_addr()).unwrap();
+    let mut tls = connector.connect("localhost", tcp).unwrap();
+
+    tls.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    tls.read_exact(&mut pong).unwrap();
+    assert_eq!(&pong, b"ping");
+
+    // Spans many records, each side reading while the other writes.
+    let payload: Vec<u8> = (0..256 * 1024).map(|i| i as u8).collect();
+    tls.write_all(&payload).unwrap();
+    let mut echoed = vec![0u8; payload.len()];
+    tls.read_exact(&mut echoed).unwrap();
+    assert!(echoed == payload);
+
+    server.shutdown().unwrap();
+    let _ = fs::remove_dir_all(cert.parent().unwrap());
+}
@@ -0,0 +1,142 @@
+// Copyright 2017 Nathan Sizemore <nathanrsizemore@gmail.com>
+//
+// This Source Code Form is subject to the terms of the
+// Mozilla Public License, v. 2.0. If a copy of the MPL was not distributed
+// with this file, you can obtain one at http://mozilla.org/MPL/2.0/.
+
+
+extern crate alnio;
+
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, IoSlice, Read, Write};
+use std::net::TcpStream;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use alnio::{Builder, Callbacks, Connection, Server};
+
+
+/// Polls `f` until it returns true, failing the test after five seconds.
+fn wait_until<F: FnMut() -> bool>(mut f: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !f() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Starts `builder` with `callbacks`, connects to it, and returns the
+/// server along with both ends of the connection.
+fn accept(builder: Builder, callbacks: Callbacks) -> (Server, TcpStream, Connection) {
+    let accepted: Arc<Mutex<Option<Connection>>> = Arc::new(Mutex::new(None));
+    let on_connect = accepted.clone();
+    let server = builder
+        .handler(callbacks.on_connect(move |conn| {
+            *on_connect.lock().unwrap() = Some(conn.clone());
+        }))
+        .start("127.0.0.1:0")
+        .unwrap();
+
+    let client = TcpStream::connect(server.local_addr()).unwrap();
+    wait_until(|| accepted.lock().unwrap().is_some());
+    let conn = accepted.lock().unwrap().take().unwrap();
+    (server, client, conn)
+}
+
+/// Returns `len` bytes that differ from those of any other `n`.
+fn pattern(n: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| (n * 31 + i) as u8).collect()
+}
+
+#[test]
+fn send_vectored_keeps_order_across_many_segments() {
+    let (_server, mut client, conn) = accept(Builder::new(), Callbacks::new());
+
+    // Shared payloads each stay a segment of their own, so the transmit
+    // buffer holds far more of them than a single writev takes.
+    let mut expected = Vec::new();
+    conn.cork().unwrap();
+    for n in 0..150 {
+        let payload = pattern(n, 100 + n);
+        expected.extend_from_slice(&payload);
+        if n % 2 == 0 {
+            conn.send_shared(payload.into()).unwrap();
+        } else {
+            conn.send(&payload).unwrap();
+        }
+    }
+
+    let slices: Vec<Vec<u8>> = (150..350).map(|n| pattern(n, 1 + n % 7)).collect();
+    let bufs: Vec<IoSlice> = slices.iter().map(|s| IoSlice::new(s)).collect();
+    for s in slices.iter() { expected.extend_from_slice(s); }
+    conn.send_vectored(&bufs).unwrap();
+    conn.uncork().unwrap();
+
+    let mut received = vec![0u8; expected.len()];
+    client.read_exact(&mut received).unwrap();
+    assert!(received == expected);
+}
+
+#[test]
+fn send_file_between_buffered_bytes() {
+    let sent_files = Arc::new(Mutex::new(Vec::new()));
+    let on_file_sent = sent_files.clone();
+    let (_server, mut client, conn) = accept(
+        Builder::new(),
+        Callbacks::new().on_file_sent(move |_, file| {
+            on_file_sent.lock().unwrap().push(file);
+        }));
+
+    let contents = pattern(0, 1 << 20);
+    let path = env::temp_dir().join(format!("alnio-send-file-{}", process::id()));
+    File::create(&path).unwrap().write_all(&contents).unwrap();
+    let file = File::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // Corked, so that the region is queued behind the first send rather
+    // than written straight through.
+    conn.cork().unwrap();
+    conn.send(b"head").unwrap();
+    conn.send_file(file, 100, 512 * 1024).unwrap();
+    conn.send(b"tail").unwrap();
+    conn.uncork().unwrap();
+
+    let mut expected = b"head".to_vec();
+    expected.extend_from_slice(&contents[100..100 + 512 * 1024]);
+    expected.extend_from_slice(b"tail");
+    let mut received = vec![0u8; expected.len()];
+    client.read_exact(&mut received).unwrap();
+    assert!(received == expected);
+
+    wait_until(|| sent_files.lock().unwrap().len() == 1);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(sent_files.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn zerocopy_completions_are_reaped() {
+    let (_server, mut client, conn) = accept(Builder::new(), Callbacks::new());
+    if let Err(err) = conn.set_zerocopy(Some(16 * 1024)) {
+        // Kernels before 4.14 have no SO_ZEROCOPY.
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        return;
+    }
+
+    let payload: Arc<[u8]> = pattern(0, 4 << 20).into();
+    for _ in 0..4 { conn.send_shared(payload.clone()).unwrap(); }
+
+    let mut received = vec![0u8; 4 * payload.len()];
+    client.read_exact(&mut received).unwrap();
+    for chunk in received.chunks(payload.len()) {
+        assert!(chunk == &payload[..]);
+    }
+
+    // Completions arrive on the error queue after the bytes themselves,
+    // each releases the payload it was sent from.
+    wait_until(|| conn.zerocopy_pending() == 0);
+    assert_eq!(Arc::strong_count(&payload), 1);
+}